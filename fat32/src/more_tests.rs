@@ -118,4 +118,276 @@ mod ebpb {
     test_ebpb_field!(volume_label, 71, 11, [0xFF; 11], [0xFF; 11]);
 
     test_ebpb_field!(system_id, 82, 8, [0xFF; 8], [0xFF; 8]);
+
+    #[test]
+    fn active_fat_flags() {
+        let mut buf: [u8; 512] = [0; 512];
+        buf[510..512].copy_from_slice(&[0x55, 0xAA]);
+        let ebpb = BiosParameterBlock::from(Cursor::new(&mut buf[..]), 0).unwrap();
+        assert!(!ebpb.mirroring_disabled());
+
+        buf[40] = 0x83;
+        let ebpb = BiosParameterBlock::from(Cursor::new(&mut buf[..]), 0).unwrap();
+        assert!(ebpb.mirroring_disabled());
+        assert_eq!(ebpb.active_fat(), 3);
+    }
+}
+
+mod fsinfo {
+    use std::io::Cursor;
+    use vfat::{Error, FsInfo};
+
+    fn fs_info_sector() -> [u8; 512] {
+        let mut buf: [u8; 512] = [0; 512];
+        buf[0..4].copy_from_slice(&[0x52, 0x52, 0x61, 0x41]);
+        buf[484..488].copy_from_slice(&[0x72, 0x72, 0x41, 0x61]);
+        buf[488..492].copy_from_slice(&[0x10, 0x00, 0x00, 0x00]);
+        buf[492..496].copy_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF]);
+        buf[508..512].copy_from_slice(&[0x00, 0x00, 0x55, 0xAA]);
+        buf
+    }
+
+    #[test]
+    fn fields() {
+        let mut buf = fs_info_sector();
+        let mut fs_info = FsInfo::from(Cursor::new(&mut buf[..]), 0).expect("valid FSInfo");
+        assert_eq!(fs_info.free_count(), Some(0x10));
+        assert_eq!(fs_info.next_free(), None);
+
+        fs_info.set_free_count(None);
+        fs_info.set_next_free(Some(0x1234));
+        assert_eq!(&fs_info.as_bytes()[488..496], &[0xFF, 0xFF, 0xFF, 0xFF, 0x34, 0x12, 0, 0]);
+    }
+
+    #[test]
+    fn signatures() {
+        for &offset in [0, 484, 511].iter() {
+            let mut buf = fs_info_sector();
+            buf[offset] ^= 0xFF;
+            match FsInfo::from(Cursor::new(&mut buf[..]), 0) {
+                Err(Error::BadSignature) => {}
+                other => panic!("expected BadSignature but found {:?}", other),
+            }
+        }
+    }
+}
+
+mod short_name {
+    use std::cmp::min;
+    use std::collections::HashSet;
+    use std::mem::transmute;
+    use vfat::dir::VFatLfnDirEntry;
+    use vfat::short_name::{checksum, generate};
+
+    fn generated(name: &str, taken: &HashSet<[u8; 11]>) -> String {
+        String::from_utf8(generate(name, taken).unwrap().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn lossless() {
+        let taken = HashSet::new();
+        assert_eq!(generated("README.TXT", &taken), "README  TXT");
+        assert_eq!(generated("readme.txt", &taken), "README  TXT");
+        assert_eq!(generated("Makefile", &taken), "MAKEFILE   ");
+    }
+
+    #[test]
+    fn numeric_tails() {
+        let mut taken = HashSet::new();
+        assert_eq!(generated("long file name.text", &taken), "LONGFI~1TEX");
+        assert_eq!(generated(".bashrc", &taken), "BASHRC~1   ");
+        assert_eq!(generated("a+b.c", &taken), "A_B~1   C  ");
+        assert_eq!(generated("...", &taken), "_~1        ");
+
+        taken.insert(*b"README  TXT");
+        assert_eq!(generated("readme.txt", &taken), "README~1TXT");
+
+        for i in 1..5 {
+            let short_name = generate("long file name.text", &taken).unwrap();
+            assert_eq!(&short_name[..], format!("LONGFI~{}TEX", i).as_bytes());
+            taken.insert(short_name);
+        }
+    }
+
+    #[test]
+    fn hashed_tails() {
+        let mut taken = HashSet::new();
+        for _ in 0..4 {
+            taken.insert(generate("collision storm", &taken).unwrap());
+        }
+
+        let hashed = generated("collision storm", &taken);
+        assert!(hashed.starts_with("CO"));
+        assert!(hashed[2..6].chars().all(|c| c.is_digit(16)));
+        assert_eq!(&hashed[6..], "~1   ");
+
+        for _ in 0..9 {
+            taken.insert(generate("collision storm", &taken).unwrap());
+        }
+        assert_eq!(generated("collision storm", &taken), "COLLIS~5   ");
+    }
+
+    #[test]
+    fn exhausted() {
+        // Every numeric tail of "XY", which loses characters of its base
+        // once the tail is long enough.
+        let mut taken: HashSet<[u8; 11]> = (1..1_000_000)
+            .map(|i| {
+                let tail = format!("~{}", i);
+                let keep = min(2, 8 - tail.len());
+                let mut short_name = [b' '; 11];
+                short_name[..keep].copy_from_slice(&b"XY"[..keep]);
+                short_name[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+                short_name
+            }).collect();
+
+        // The nine hashed tails are all that is left.
+        for _ in 0..9 {
+            let short_name = generate("x y", &taken).unwrap();
+            assert_eq!(&short_name[..2], b"XY");
+            assert!(taken.insert(short_name));
+        }
+        assert!(generate("x y", &taken).is_err());
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(checksum(b"           "), 0xF7);
+        assert_eq!(checksum(b"README  TXT"), 0x73);
+    }
+
+    #[test]
+    fn lfn_sequence() {
+        let sum = checksum(b"ABCDEF~1TXT");
+        let name = "abcdefghijklmnopqrstuvwxyz0.txt";
+        let entries: Vec<[u8; 32]> = VFatLfnDirEntry::sequence(name, sum)
+            .into_iter()
+            .map(|entry| unsafe { transmute::<VFatLfnDirEntry, [u8; 32]>(entry) })
+            .collect();
+
+        let seqnos: Vec<u8> = entries.iter().map(|entry| entry[0]).collect();
+        assert_eq!(seqnos, vec![0x43, 0x02, 0x01]);
+        assert!(entries.iter().all(|entry| entry[11] == 0x0F && entry[13] == sum));
+
+        // The last entry holds "0.txt", a terminating NUL and 0xFFFF padding.
+        assert_eq!(&entries[0][1..11], &[b'0', 0, b'.', 0, b't', 0, b'x', 0, b't', 0]);
+        assert_eq!(&entries[0][14..18], &[0, 0, 0xFF, 0xFF]);
+
+        // A name filling its entries exactly has neither terminator nor padding.
+        let entries = VFatLfnDirEntry::sequence("abcdefghijklm", sum);
+        assert_eq!(entries.len(), 1);
+        let entry = unsafe { transmute::<VFatLfnDirEntry, [u8; 32]>(entries[0]) };
+        assert_eq!(entry[0], 0x41);
+        assert_eq!(&entry[28..32], &[b'l', 0, b'm', 0]);
+    }
+}
+
+mod timestamp {
+    use std::time::{Duration, UNIX_EPOCH};
+    use traits::Timestamp as TimestampTrait;
+    use vfat::{Clock, Date, FixedClock, ManualClock, Time, Timestamp};
+
+    fn components(ts: Timestamp) -> (usize, u8, u8, u8, u8, u8, u8) {
+        (
+            ts.year(),
+            ts.month(),
+            ts.day(),
+            ts.hour(),
+            ts.minute(),
+            ts.second(),
+            ts.centisecond(),
+        )
+    }
+
+    #[test]
+    fn constructors() {
+        assert_eq!(Date::new(1980, 1, 1), Some(Date::from_raw(0x21)));
+        assert_eq!(Date::new(2000, 2, 29).map(|d| d.as_raw()), Some(20 << 9 | 2 << 5 | 29));
+        assert_eq!(Date::new(2107, 12, 31).map(|d| d.as_raw()), Some(0xFF9F));
+        assert_eq!(Date::new(1979, 12, 31), None);
+        assert_eq!(Date::new(2108, 1, 1), None);
+        assert_eq!(Date::new(2001, 2, 29), None);
+        assert_eq!(Date::new(2100, 2, 29), None);
+        assert_eq!(Date::new(2019, 4, 31), None);
+        assert_eq!(Date::new(2019, 0, 1), None);
+        assert_eq!(Date::new(2019, 13, 1), None);
+        assert_eq!(Date::new(2019, 1, 0), None);
+
+        assert_eq!(Time::new(23, 59, 59), Time::new(23, 59, 58));
+        assert_eq!(Time::new(13, 7, 42).map(|t| t.as_raw()), Some(13 << 11 | 7 << 5 | 21));
+        assert_eq!(Time::new(24, 0, 0), None);
+        assert_eq!(Time::new(0, 60, 0), None);
+        assert_eq!(Time::new(0, 0, 60), None);
+
+        let ts = Timestamp::from_calendar(2018, 6, 30, 12, 34, 57, 89).expect("valid");
+        assert_eq!(components(ts), (2018, 6, 30, 12, 34, 57, 89));
+        assert_eq!(ts.centiseconds, 189);
+        assert_eq!(Timestamp::from_calendar(2018, 6, 30, 12, 34, 57, 100), None);
+        assert_eq!(Timestamp::from_calendar(2018, 6, 31, 12, 34, 57, 0), None);
+    }
+
+    #[test]
+    fn from_unix() {
+        let fat_epoch = (1980, 1, 1, 0, 0, 0, 0);
+        assert_eq!(components(Timestamp::from_unix(315_532_800, 0)), fat_epoch);
+        assert_eq!(components(Timestamp::from_unix(0, 0)), fat_epoch);
+        assert_eq!(components(Timestamp::from_unix(-1, 0)), fat_epoch);
+
+        let leap_day = Timestamp::from_unix(951_782_400 + 3_661, 990_000_000);
+        assert_eq!(components(leap_day), (2000, 2, 29, 1, 1, 1, 99));
+
+        let new_years_eve = Timestamp::from_unix(1_735_689_599, 5_000_000);
+        assert_eq!(components(new_years_eve), (2024, 12, 31, 23, 59, 59, 0));
+
+        let last = (2107, 12, 31, 23, 59, 59, 0);
+        assert_eq!(components(Timestamp::from_unix(4_354_819_199, 0)), last);
+        let last = (2107, 12, 31, 23, 59, 59, 99);
+        assert_eq!(components(Timestamp::from_unix(u32::max_value() as i64 * 2, 0)), last);
+        assert_eq!(components(Timestamp::from_unix(1 << 40, 0)), last);
+    }
+
+    #[test]
+    fn unix_round_trip() {
+        for &(secs, nanos) in &[
+            (315_532_800, 0),
+            (951_782_400 + 3_661, 990_000_000),
+            (1_500_000_000, 250_000_000),
+            (1_735_689_599, 0),
+            (4_354_819_199, 0),
+        ] {
+            assert_eq!(Timestamp::from_unix(secs, nanos).to_unix(), (secs, nanos));
+        }
+
+        let modified = Timestamp::new(Date::from_raw(0x4CEE), Time::from_raw(0x7A6F));
+        assert_eq!(Timestamp::from_unix(modified.to_unix().0, 0), modified);
+    }
+
+    #[test]
+    fn fixed_and_manual() {
+        let then = Timestamp::from_unix(1_000_000_000, 0);
+        let now = Timestamp::from_unix(1_500_000_000, 0);
+        assert_eq!(FixedClock(then).now(), then);
+
+        let clock = ManualClock::new(then);
+        let handle = clock.clone();
+        assert_eq!(clock.now(), then);
+        handle.set(now);
+        assert_eq!(clock.now(), now);
+    }
+
+    #[test]
+    fn system_time() {
+        let time = UNIX_EPOCH + Duration::new(1_500_000_000, 123_456_789);
+        let utc = Timestamp::from_system_time(time, 0);
+        assert_eq!(components(utc), (2017, 7, 14, 2, 40, 0, 12));
+        assert_eq!(utc.to_system_time(0), UNIX_EPOCH + Duration::new(1_500_000_000, 120_000_000));
+
+        let offset = -7 * 3_600;
+        let local = Timestamp::from_system_time(time, offset);
+        assert_eq!(components(local), (2017, 7, 13, 19, 40, 0, 12));
+        assert_eq!(local.to_system_time(offset), utc.to_system_time(0));
+
+        let early = Timestamp::from_system_time(UNIX_EPOCH, 0);
+        assert_eq!(components(early), (1980, 1, 1, 0, 0, 0, 0));
+    }
 }
@@ -6,7 +6,7 @@ use std::path::Path;
 
 use mbr::{MasterBootRecord, PartitionEntry, CHS};
 use traits::*;
-use vfat::{BiosParameterBlock, Shared, VFat};
+use vfat::{BiosParameterBlock, FsInfo, Shared, VFat};
 
 macro check_size($T:ty, $size:expr) {
     assert_eq!(
@@ -153,6 +153,11 @@ fn test_ebpb() {
     BiosParameterBlock::from(Cursor::new(&mut data[..]), 1).expect("valid EBPB");
 }
 
+#[test]
+fn check_fs_info_size() {
+    check_size!(FsInfo, 512);
+}
+
 #[test]
 fn check_entry_sizes() {
     check_size!(::vfat::dir::VFatRegularDirEntry, 32);
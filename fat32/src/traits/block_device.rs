@@ -61,9 +61,74 @@ pub trait BlockDevice: Send {
     /// error of `UnexpectedEof` if the length of `buf` is less than
     /// `self.sector_size()`.
     fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize>;
+
+    /// Read the `count` sectors starting at sector number `start` into `buf`.
+    ///
+    /// `count * self.logical_sector_size()` bytes are read into `buf`. The
+    /// number of bytes read is returned. The default implementation reads one
+    /// sector at a time with `read_sector()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidInput` if `buf` is shorter than the sectors
+    /// being read, or an error if seeking or reading from `self` fails.
+    fn read_sectors(&mut self, start: u64, count: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let sector_size = self.logical_sector_size() as usize;
+        let len = check_sectors_buf(sector_size, count, buf.len())?;
+
+        let mut read = 0;
+        for (i, chunk) in buf[..len].chunks_mut(sector_size).enumerate() {
+            read += self.read_sector(start + i as u64, chunk)?;
+        }
+        Ok(read)
+    }
+
+    /// Overwrites the `count` sectors starting at sector number `start` with
+    /// the contents of `buf`.
+    ///
+    /// `count * self.logical_sector_size()` bytes are written from `buf`. The
+    /// number of bytes written is returned. The default implementation writes
+    /// one sector at a time with `write_sector()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidInput` if `buf` is shorter than the sectors
+    /// being written, or an error if seeking or writing to `self` fails.
+    fn write_sectors(&mut self, start: u64, count: u64, buf: &[u8]) -> io::Result<usize> {
+        let sector_size = self.logical_sector_size() as usize;
+        let len = check_sectors_buf(sector_size, count, buf.len())?;
+
+        let mut written = 0;
+        for (i, chunk) in buf[..len].chunks(sector_size).enumerate() {
+            written += self.write_sector(start + i as u64, chunk)?;
+        }
+        Ok(written)
+    }
+}
+
+/// Returns the number of bytes in `count` sectors of `sector_size` bytes, or
+/// an error of `InvalidInput` if a buffer of `buf_len` bytes cannot hold them.
+fn check_sectors_buf(sector_size: usize, count: u64, buf_len: usize) -> io::Result<usize> {
+    let len = sector_size as u64 * count;
+    if len > buf_len as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "insufficient buffer capacity",
+        ));
+    }
+
+    Ok(len as usize)
 }
 
 impl<'a, T: BlockDevice> BlockDevice for &'a mut T {
+    fn sector_size(&self) -> u64 {
+        (**self).sector_size()
+    }
+
+    fn logical_sector_size(&self) -> u64 {
+        (**self).logical_sector_size()
+    }
+
     fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
         (*self).read_sector(n, buf)
     }
@@ -71,6 +136,14 @@ impl<'a, T: BlockDevice> BlockDevice for &'a mut T {
     fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
         (*self).write_sector(n, buf)
     }
+
+    fn read_sectors(&mut self, start: u64, count: u64, buf: &mut [u8]) -> io::Result<usize> {
+        (*self).read_sectors(start, count, buf)
+    }
+
+    fn write_sectors(&mut self, start: u64, count: u64, buf: &[u8]) -> io::Result<usize> {
+        (*self).write_sectors(start, count, buf)
+    }
 }
 
 macro impl_for_read_write_seek($(<$($gen:tt),*>)* $T:path) {
@@ -92,6 +165,22 @@ macro impl_for_read_write_seek($(<$($gen:tt),*>)* $T:path) {
             self.write_all(&buf[..to_write])?;
             Ok(to_write)
         }
+
+        fn read_sectors(&mut self, start: u64, count: u64, buf: &mut [u8]) -> io::Result<usize> {
+            let sector_size = self.sector_size();
+            let len = check_sectors_buf(sector_size as usize, count, buf.len())?;
+            self.seek(io::SeekFrom::Start(start * sector_size))?;
+            self.read_exact(&mut buf[..len])?;
+            Ok(len)
+        }
+
+        fn write_sectors(&mut self, start: u64, count: u64, buf: &[u8]) -> io::Result<usize> {
+            let sector_size = self.sector_size();
+            let len = check_sectors_buf(sector_size as usize, count, buf.len())?;
+            self.seek(io::SeekFrom::Start(start * sector_size))?;
+            self.write_all(&buf[..len])?;
+            Ok(len)
+        }
     }
 }
 
@@ -160,8 +160,9 @@ pub trait FileSystem: Sized {
     /// If `from` or `to` are not absolute, an error kind of `InvalidInput` is
     /// returned.
     ///
-    /// If an entry at `to` already exists, an error kind of `AlreadyExists` is
-    /// returned.
+    /// If an entry at `to` already exists and either it or the entry at
+    /// `from` is a directory, an error kind of `AlreadyExists` is returned. An
+    /// existing file at `to` is replaced by a file at `from`.
     ///
     /// If there is no entry at `from`, an error kind of `NotFound` is returned.
     ///
@@ -179,8 +180,8 @@ pub trait FileSystem: Sized {
     ///
     /// If there is no entry at `path`, an error kind of `NotFound` is returned.
     ///
-    /// If the entry at `path` is a directory and `children` is `false`, an
-    /// error kind of `Other` is returned.
+    /// If the entry at `path` is a non-empty directory and `children` is
+    /// `false`, an error kind of `Other` is returned.
     ///
     /// All other error values are implementation defined.
     fn remove<P: AsRef<Path>>(self, path: P, children: bool) -> io::Result<()>;
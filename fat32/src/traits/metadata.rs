@@ -21,6 +21,13 @@ pub trait Timestamp: Copy + Clone + Sized {
 
     /// The second. Always in range [0, 60).
     fn second(&self) -> u8;
+
+    /// Hundredths of a second past `second()`. Always in range [0, 100).
+    ///
+    /// Timestamps without sub-second precision always return 0.
+    fn centisecond(&self) -> u8 {
+        0
+    }
 }
 
 /// Trait for directory entry metadata.
@@ -1,12 +1,72 @@
-use std::collections::hash_map::{Entry, HashMap};
+use std::cmp::min;
+use std::collections::{BTreeMap, HashMap};
 use std::{fmt, io};
 
 use traits::BlockDevice;
 
-#[derive(Debug)]
 struct CacheEntry {
     data: Vec<u8>,
     dirty: bool,
+    region: Region,
+    /// The value of `CachedDevice::uses` when the sector was last accessed.
+    last_use: u64,
+}
+
+/// The number of physical sectors a `CachedDevice` holds by default.
+pub const DEFAULT_CAPACITY: usize = 4096;
+
+/// The number of physical sectors a `CachedDevice` reads ahead of sequential
+/// accesses by default.
+pub const DEFAULT_READ_AHEAD: usize = 8;
+
+/// The region of the disk a cached sector belongs to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Region {
+    /// Sectors before the FAT: the MBR, boot sector, FSInfo sector and other
+    /// reserved sectors.
+    Reserved,
+    /// Sectors of the file allocation tables.
+    Fat,
+    /// Sectors of clusters holding directory entries.
+    Directory,
+    /// Sectors of clusters holding file data, and any other sectors of the
+    /// data region.
+    Data,
+}
+
+/// Statistics about the use of a sector cache.
+///
+/// `hits`, `misses`, `evictions`, `prefetched`, `bypassed`, `bytes_read` and
+/// `bytes_written` count events since the cache was created or its statistics
+/// were last reset. The remaining fields describe the sectors cached at the
+/// time the statistics were taken.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The number of sector accesses served from the cache.
+    pub hits: u64,
+    /// The number of sector accesses that read the sector from the device.
+    pub misses: u64,
+    /// The number of sectors evicted to make room for others.
+    pub evictions: u64,
+    /// The number of sectors read ahead of sequential accesses.
+    pub prefetched: u64,
+    /// The number of sectors read straight into a caller's buffer, bypassing
+    /// the cache.
+    pub bypassed: u64,
+    /// The number of bytes read from the device.
+    pub bytes_read: u64,
+    /// The number of bytes written to the device.
+    pub bytes_written: u64,
+    /// The number of cached sectors not yet written back to the device.
+    pub dirty_sectors: usize,
+    /// The number of cached reserved sectors.
+    pub reserved_sectors: usize,
+    /// The number of cached FAT sectors.
+    pub fat_sectors: usize,
+    /// The number of cached directory sectors.
+    pub directory_sectors: usize,
+    /// The number of cached data sectors.
+    pub data_sectors: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -20,6 +80,21 @@ pub struct Partition {
 pub struct CachedDevice {
     device: Box<BlockDevice>,
     cache: HashMap<u64, CacheEntry>,
+    /// The cached sectors keyed by their last use, least recently used first.
+    lru: BTreeMap<u64, u64>,
+    /// The number of accesses made to the cache so far.
+    uses: u64,
+    /// The maximum number of physical sectors held in the cache.
+    capacity: usize,
+    /// The number of physical sectors read ahead of sequential accesses.
+    read_ahead: usize,
+    /// The physical sector that continues the current run of sequential
+    /// accesses, if any.
+    next_sequential: Option<u64>,
+    /// The first physical sectors of the FAT and the data regions.
+    fat_start: u64,
+    data_start: u64,
+    stats: CacheStats,
     partition: Partition,
 }
 
@@ -40,22 +115,328 @@ impl CachedDevice {
     /// `partition.sector_size` must be an integer multiple of
     /// `device.sector_size()`.
     ///
+    /// At most `DEFAULT_CAPACITY` physical sectors are cached at once, and
+    /// `DEFAULT_READ_AHEAD` sectors are read ahead of sequential accesses.
+    ///
     /// # Panics
     ///
     /// Panics if the partition's sector size is < the device's sector size.
     pub fn new<T>(device: T, partition: Partition) -> CachedDevice
+    where
+        T: BlockDevice + 'static,
+    {
+        CachedDevice::with_capacity(device, partition, DEFAULT_CAPACITY)
+    }
+
+    /// Like `new()`, but caches at most `capacity` physical sectors at once.
+    /// Once the cache is full, the least recently used sector is evicted to
+    /// make room for another, and written back to the device first if it is
+    /// dirty.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the partition's sector size is < the device's sector size or
+    /// if `capacity` is zero.
+    pub fn with_capacity<T>(device: T, partition: Partition, capacity: usize) -> CachedDevice
     where
         T: BlockDevice + 'static,
     {
         assert!(partition.sector_size >= device.sector_size());
+        assert!(capacity > 0);
 
         CachedDevice {
             device: Box::new(device),
             cache: HashMap::new(),
+            lru: BTreeMap::new(),
+            uses: 0,
+            capacity,
+            read_ahead: DEFAULT_READ_AHEAD,
+            next_sequential: None,
+            fat_start: 0,
+            data_start: 0,
+            stats: CacheStats::default(),
             partition: partition,
         }
     }
 
+    /// The number of physical sectors read ahead of sequential accesses.
+    pub fn read_ahead(&self) -> usize {
+        self.read_ahead
+    }
+
+    /// Sets the number of physical sectors read ahead of sequential accesses
+    /// to `sectors`, or turns read-ahead off if `sectors` is zero.
+    ///
+    /// When a sector that is not cached immediately follows the last sector
+    /// read from the device, up to `sectors` of the sectors after it are read
+    /// into the cache along with it. Read-ahead never exceeds the cache's
+    /// capacity and stops at the first sector that is already cached.
+    pub fn set_read_ahead(&mut self, sectors: usize) {
+        self.read_ahead = sectors;
+    }
+
+    /// Sets the logical sectors at which the FAT and the data regions begin,
+    /// used to attribute newly cached sectors to a region. Until this is
+    /// called, every sector is attributed to `Region::Data`.
+    pub fn set_layout(&mut self, fat_start: u64, data_start: u64) {
+        self.fat_start = self.virtual_to_physical(fat_start).0;
+        self.data_start = self.virtual_to_physical(data_start).0;
+    }
+
+    /// Attributes the physical sectors making up the logical sector `sector`
+    /// to `region` if they are cached. Sectors of the data region are
+    /// attributed to `Region::Data` when first cached; the file system marks
+    /// the ones holding directory entries.
+    pub fn set_region(&mut self, sector: u64, region: Region) {
+        let (sector, factor) = self.virtual_to_physical(sector);
+        for sector in sector..sector + factor {
+            if let Some(cache_entry) = self.cache.get_mut(&sector) {
+                cache_entry.region = region;
+            }
+        }
+    }
+
+    /// Returns statistics about the use of the cache.
+    pub fn stats(&self) -> CacheStats {
+        let mut stats = self.stats;
+        for cache_entry in self.cache.values() {
+            if cache_entry.dirty {
+                stats.dirty_sectors += 1;
+            }
+
+            match cache_entry.region {
+                Region::Reserved => stats.reserved_sectors += 1,
+                Region::Fat => stats.fat_sectors += 1,
+                Region::Directory => stats.directory_sectors += 1,
+                Region::Data => stats.data_sectors += 1,
+            }
+        }
+
+        stats
+    }
+
+    /// Resets the event counters of `stats()` to zero.
+    pub fn reset_stats(&mut self) {
+        self.stats = CacheStats::default();
+    }
+
+    /// Returns the region the physical sector `sector` lies in according to
+    /// the layout set with `set_layout()`.
+    fn region(&self, sector: u64) -> Region {
+        if sector >= self.data_start {
+            Region::Data
+        } else if sector >= self.fat_start {
+            Region::Fat
+        } else {
+            Region::Reserved
+        }
+    }
+
+    /// The maximum number of physical sectors held in the cache.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Sets the maximum number of physical sectors held in the cache to
+    /// `capacity`, evicting the least recently used sectors as needed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidInput` if `capacity` is zero, or an error if
+    /// there is an error writing an evicted sector back to the disk. In the
+    /// latter case the capacity is still changed and the remaining excess
+    /// sectors are evicted as more sectors are cached.
+    pub fn set_capacity(&mut self, capacity: usize) -> io::Result<()> {
+        if capacity == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cache capacity must be non-zero",
+            ));
+        }
+
+        self.capacity = capacity;
+        while self.cache.len() > self.capacity {
+            self.evict()?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the cache entry for the physical sector `sector`, reading the
+    /// sector from the disk first if it is not already cached, and marks it as
+    /// the most recently used.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an error reading the sector from the disk
+    /// or writing an evicted sector back to it.
+    fn entry(&mut self, sector: u64) -> io::Result<&mut CacheEntry> {
+        if self.cache.contains_key(&sector) {
+            self.stats.hits += 1;
+            self.touch(sector);
+            if self.next_sequential == Some(sector) {
+                self.next_sequential = Some(sector + 1);
+            }
+        } else {
+            self.load(sector)?;
+            self.stats.misses += 1;
+            let prefetched = match self.next_sequential {
+                Some(next) if next == sector => self.prefetch(sector + 1),
+                _ => 0,
+            };
+            self.next_sequential = Some(sector + 1 + prefetched as u64);
+        }
+
+        Ok(self.cache.get_mut(&sector).expect("sector is cached"))
+    }
+
+    /// Reads the physical sector `sector` from the disk into the cache as the
+    /// most recently used sector.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an error reading the sector from the disk
+    /// or writing an evicted sector back to it.
+    fn load(&mut self, sector: u64) -> io::Result<()> {
+        let mut data = vec![];
+        self.stats.bytes_read += self.device.read_all_sector(sector, &mut data)? as u64;
+        self.insert(sector, data)
+    }
+
+    /// Adds the physical sector `sector`, just read from the disk as `data`, to
+    /// the cache as a clean, most recently used sector, evicting the least
+    /// recently used sector first if the cache is full.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an error writing an evicted sector back to
+    /// the disk.
+    fn insert(&mut self, sector: u64, data: Vec<u8>) -> io::Result<()> {
+        while self.cache.len() >= self.capacity {
+            self.evict()?;
+        }
+
+        let region = self.region(sector);
+        self.cache.insert(
+            sector,
+            CacheEntry {
+                data,
+                dirty: false,
+                region,
+                last_use: 0,
+            },
+        );
+
+        self.touch(sector);
+        Ok(())
+    }
+
+    /// Reads up to `self.read_ahead` sectors starting at `start` into the
+    /// cache with a single read, stopping at the first sector that is already
+    /// cached or cannot be read. At least one sector of the cache is left to
+    /// the sector being accessed. Returns the number of sectors read.
+    fn prefetch(&mut self, start: u64) -> usize {
+        let limit = min(self.read_ahead, self.capacity - 1) as u64;
+        let count = (0..limit)
+            .take_while(|&i| !self.cache.contains_key(&(start + i)))
+            .count() as u64;
+        if count == 0 {
+            return 0;
+        }
+
+        let sector_size = self.device.sector_size() as usize;
+        let mut buf = vec![0; count as usize * sector_size];
+        let prefetched = match self.device.read_sectors(start, count, &mut buf) {
+            Ok(read) => {
+                self.stats.bytes_read += read as u64;
+                buf.chunks(sector_size)
+                    .enumerate()
+                    .take_while(|&(i, data)| self.insert(start + i as u64, data.to_vec()).is_ok())
+                    .count()
+            }
+            // A read running past the end of the device fails as a whole, so
+            // fall back to reading one sector at a time.
+            Err(_) => (0..count)
+                .take_while(|&i| self.load(start + i).is_ok())
+                .count(),
+        };
+
+        self.stats.prefetched += prefetched as u64;
+        prefetched
+    }
+
+    /// Copies the physical sectors `start..start + count` into `buf`, reading
+    /// each run of sectors that are not cached from the device with a single
+    /// read and caching them.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an error reading a sector from the disk
+    /// or writing an evicted sector back to it.
+    fn read_physical(&mut self, start: u64, count: u64, buf: &mut [u8]) -> io::Result<()> {
+        let sector_size = self.device.sector_size() as usize;
+        let mut i = 0;
+        while i < count {
+            let sector = start + i;
+            let offset = i as usize * sector_size;
+            if self.cache.contains_key(&sector) {
+                let cache_entry = self.entry(sector)?;
+                buf[offset..offset + sector_size].copy_from_slice(&cache_entry.data[..sector_size]);
+                i += 1;
+                continue;
+            }
+
+            let run = (i..count)
+                .take_while(|&j| !self.cache.contains_key(&(start + j)))
+                .count() as u64;
+            let chunk = &mut buf[offset..offset + run as usize * sector_size];
+            self.stats.bytes_read += self.device.read_sectors(sector, run, chunk)? as u64;
+            self.stats.misses += run;
+            for (j, data) in chunk.chunks(sector_size).enumerate() {
+                self.insert(sector + j as u64, data.to_vec())?;
+            }
+
+            self.next_sequential = Some(sector + run);
+            i += run;
+        }
+
+        Ok(())
+    }
+
+    /// Marks the cached physical sector `sector` as the most recently used.
+    fn touch(&mut self, sector: u64) {
+        self.uses += 1;
+        let cache_entry = self.cache.get_mut(&sector).expect("sector is cached");
+        self.lru.remove(&cache_entry.last_use);
+        cache_entry.last_use = self.uses;
+        self.lru.insert(self.uses, sector);
+    }
+
+    /// Removes the least recently used sector from the cache, writing it back
+    /// to the disk first if it is dirty.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an error writing the sector to the disk.
+    /// The sector then remains cached.
+    fn evict(&mut self) -> io::Result<()> {
+        let (last_use, sector) = match self.lru.iter().next() {
+            Some((&last_use, &sector)) => (last_use, sector),
+            None => return Ok(()),
+        };
+
+        let sector_size = self.device.sector_size() as usize;
+        if self.cache[&sector].dirty {
+            let written = self.device.write_sector(sector, &self.cache[&sector].data[..sector_size])?;
+            self.stats.bytes_written += written as u64;
+        }
+
+        self.lru.remove(&last_use);
+        self.cache.remove(&sector);
+        self.stats.evictions += 1;
+        Ok(())
+    }
+
     /// Maps a user's request for a sector `virt` to the physical sector and
     /// number of physical sectors required to access `virt`.
     fn virtual_to_physical(&self, virt: u64) -> (u64, u64) {
@@ -81,22 +462,13 @@ impl CachedDevice {
     ///
     /// # Errors
     ///
-    /// Returns an error if there is an error reading the sector from the disk.
+    /// Returns an error if there is an error reading the sector from the disk
+    /// or writing an evicted sector back to it.
     pub fn get_mut(&mut self, sector: u64) -> io::Result<&mut [u8]> {
         let sector_size = self.device.sector_size() as usize;
-        match self.cache.entry(sector) {
-            Entry::Occupied(occupied) => {
-                let cache_entry = occupied.into_mut();
-                cache_entry.dirty = true;
-                Ok(&mut cache_entry.data[..sector_size])
-            }
-            Entry::Vacant(vacant) => {
-                let mut data = Vec::with_capacity(sector_size);
-                self.device.read_sector(sector, &mut data[..sector_size])?;
-                let cache_entry = vacant.insert(CacheEntry { data, dirty: true });
-                Ok(&mut cache_entry.data[..sector_size])
-            }
-        }
+        let cache_entry = self.entry(sector)?;
+        cache_entry.dirty = true;
+        Ok(&mut cache_entry.data[..sector_size])
     }
 
     /// Returns a reference to the cached sector `sector`. If the sector is not
@@ -104,42 +476,144 @@ impl CachedDevice {
     ///
     /// # Errors
     ///
-    /// Returns an error if there is an error reading the sector from the disk.
+    /// Returns an error if there is an error reading the sector from the disk
+    /// or writing an evicted sector back to it.
     pub fn get(&mut self, sector: u64) -> io::Result<&[u8]> {
         let sector_size = self.device.sector_size() as usize;
-        match self.cache.entry(sector) {
-            Entry::Occupied(occupied) => {
-                let cache_entry = occupied.into_mut();
-                Ok(&cache_entry.data[..sector_size])
-            }
-            Entry::Vacant(vacant) => {
-                let mut data = vec![];
-                self.device.read_all_sector(sector, &mut data)?;
-                let cache_entry = vacant.insert(CacheEntry { data, dirty: false });
-                Ok(&cache_entry.data[..sector_size])
-            }
+        let cache_entry = self.entry(sector)?;
+        Ok(&cache_entry.data[..sector_size])
+    }
+
+    /// Maps byte `logical_offset` of the logical sector `sector` to the
+    /// physical sector containing it and the byte offset within that sector.
+    fn logical_to_physical(&self, sector: u64, logical_offset: usize) -> io::Result<(u64, usize)> {
+        let (sector, factor) = self.virtual_to_physical(sector);
+        let factor = factor as usize;
+        let sector_size = self.device.sector_size() as usize;
+        let sector_offset = logical_offset / sector_size;
+        if sector_offset >= factor {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid logical offset",
+            ));
         }
+
+        Ok((sector + sector_offset as u64, logical_offset % sector_size))
     }
 
+    /// Returns a reference to the cached physical sector containing byte
+    /// `logical_offset` of the logical sector `sector`, along with the offset
+    /// of that byte within the returned physical sector.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `logical_offset` lies outside of the logical sector
+    /// or if there is an error reading the sector from the disk.
     pub fn get_logical(
         &mut self,
         sector: u64,
         logical_offset: usize,
     ) -> io::Result<(usize, &[u8])> {
-        let (sector, factor) = self.virtual_to_physical(sector);
-        let factor = factor as usize;
+        let (sector, physical_offset) = self.logical_to_physical(sector, logical_offset)?;
+        let sector = self.get(sector)?;
+        Ok((physical_offset, sector))
+    }
+
+    /// Like `get_logical()`, but returns a mutable reference and marks the
+    /// physical sector dirty.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `logical_offset` lies outside of the logical sector
+    /// or if there is an error reading the sector from the disk.
+    pub fn get_logical_mut(
+        &mut self,
+        sector: u64,
+        logical_offset: usize,
+    ) -> io::Result<(usize, &mut [u8])> {
+        let (sector, physical_offset) = self.logical_to_physical(sector, logical_offset)?;
+        let sector = self.get_mut(sector)?;
+        Ok((physical_offset, sector))
+    }
+
+    /// Reads the `count` logical sectors starting at `sector`, which must lie
+    /// in the partition, straight from the device into `buf` with a single
+    /// read, without caching them. Dirty cached sectors in the range are then
+    /// copied over what was read, so `buf` reflects every write made through
+    /// the cache. Returns the number of bytes read.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidInput` if `buf` is shorter than the sectors
+    /// being read, or an error if there is an error reading from the disk.
+    pub fn read_direct(&mut self, sector: u64, count: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let (physical, factor) = self.virtual_to_physical(sector);
         let sector_size = self.device.sector_size() as usize;
-        let sector_offset = logical_offset / sector_size;
-        if sector_offset > factor {
+        let count = count * factor;
+        let len = count as usize * sector_size;
+        if buf.len() < len {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
-                "invalid logical offset",
+                "insufficient buffer capacity",
             ));
         }
 
-        let physical_offset = logical_offset % sector_size;
-        let sector = self.get(sector + sector_offset as u64)?;
-        Ok((physical_offset, sector))
+        self.stats.bytes_read += self.device.read_sectors(physical, count, &mut buf[..len])? as u64;
+        self.stats.bypassed += count;
+        for i in 0..count {
+            match self.cache.get(&(physical + i)) {
+                Some(cache_entry) if cache_entry.dirty => {
+                    let offset = i as usize * sector_size;
+                    buf[offset..offset + sector_size].copy_from_slice(&cache_entry.data[..sector_size]);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(len)
+    }
+
+    /// Writes every dirty cached sector back to the device in order of sector
+    /// number and marks it clean. Each run of consecutive dirty physical
+    /// sectors is written with a single write.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an error writing a sector to the disk.
+    /// Sectors that were not written back remain dirty.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let mut dirty: Vec<u64> = self
+            .cache
+            .iter()
+            .filter(|&(_, entry)| entry.dirty)
+            .map(|(&sector, _)| sector)
+            .collect();
+        dirty.sort();
+
+        let sector_size = self.device.sector_size() as usize;
+        let mut i = 0;
+        while i < dirty.len() {
+            let start = dirty[i];
+            let run = dirty[i..]
+                .iter()
+                .enumerate()
+                .take_while(|&(j, &sector)| sector == start + j as u64)
+                .count();
+
+            let mut buf = Vec::with_capacity(run * sector_size);
+            for sector in dirty[i..i + run].iter() {
+                buf.extend_from_slice(&self.cache[sector].data[..sector_size]);
+            }
+
+            self.stats.bytes_written += self.device.write_sectors(start, run as u64, &buf)? as u64;
+            for sector in dirty[i..i + run].iter() {
+                self.cache.get_mut(sector).expect("dirty sector is cached").dirty = false;
+            }
+
+            i += run;
+        }
+
+        Ok(())
     }
 }
 
@@ -190,13 +664,43 @@ impl BlockDevice for CachedDevice {
 
         Ok(sector_size * factor)
     }
+
+    /// Reads the `count` sectors starting at `start` through the cache. Each
+    /// run of physical sectors that are not cached is read from the device
+    /// with a single read.
+    fn read_sectors(&mut self, start: u64, count: u64, buf: &mut [u8]) -> io::Result<usize> {
+        // Sectors before the partition are smaller than those in it.
+        if start < self.partition.start && start + count > self.partition.start {
+            let mut read = 0;
+            for n in start..start + count {
+                read += self.read_sector(n, &mut buf[read..])?;
+            }
+            return Ok(read);
+        }
+
+        let (physical, factor) = self.virtual_to_physical(start);
+        let len = (count * factor * self.device.sector_size()) as usize;
+        if buf.len() < len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "insufficient buffer capacity",
+            ));
+        }
+
+        self.read_physical(physical, count * factor, &mut buf[..len])?;
+        Ok(len)
+    }
 }
 
 impl fmt::Debug for CachedDevice {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("CachedDevice")
             .field("device", &"<block device>")
-            .field("cache", &self.cache)
+            .field("partition", &self.partition)
+            .field("capacity", &self.capacity)
+            .field("read_ahead", &self.read_ahead)
+            .field("cached", &self.cache.len())
+            .field("stats", &self.stats())
             .finish()
     }
 }
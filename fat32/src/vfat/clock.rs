@@ -0,0 +1,81 @@
+#[cfg(not(target_os = "ros"))]
+use std::time::SystemTime;
+
+use vfat::{Shared, Timestamp};
+
+/// A source of the current time, used to stamp directory entries as they are
+/// created, modified and accessed.
+pub trait Clock: Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> Timestamp;
+}
+
+/// A clock that always reports the same time.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct FixedClock(pub Timestamp);
+
+impl Clock for FixedClock {
+    fn now(&self) -> Timestamp {
+        self.0
+    }
+}
+
+/// A clock that reports whatever time it was last set to. Clones share their
+/// time, so a clone kept after handing a `ManualClock` to a `VFat` can be used
+/// to set the time that file system sees.
+#[derive(Debug, Clone)]
+pub struct ManualClock(Shared<Timestamp>);
+
+impl ManualClock {
+    /// Returns a new clock reporting `now`.
+    pub fn new(now: Timestamp) -> ManualClock {
+        ManualClock(Shared::new(now))
+    }
+
+    /// Sets the time reported by this clock and every clone of it to `now`.
+    pub fn set(&self, now: Timestamp) {
+        *self.0.borrow_mut() = now;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Timestamp {
+        *self.0.borrow()
+    }
+}
+
+/// The host's system clock, reported in a fixed time zone. The default is
+/// UTC.
+#[cfg(not(target_os = "ros"))]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SystemClock {
+    utc_offset: i32,
+}
+
+#[cfg(not(target_os = "ros"))]
+impl SystemClock {
+    /// Returns a system clock reporting time in the time zone `utc_offset`
+    /// seconds east of UTC.
+    pub fn new(utc_offset: i32) -> SystemClock {
+        SystemClock { utc_offset }
+    }
+}
+
+#[cfg(not(target_os = "ros"))]
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        Timestamp::from_system_time(SystemTime::now(), self.utc_offset)
+    }
+}
+
+/// Returns the clock a `VFat` uses until another is set: the system clock
+/// where there is one, and otherwise a clock fixed at 1980-01-01 00:00:00.
+#[cfg(not(target_os = "ros"))]
+pub(crate) fn default_clock() -> Box<Clock> {
+    Box::new(SystemClock::default())
+}
+
+#[cfg(target_os = "ros")]
+pub(crate) fn default_clock() -> Box<Clock> {
+    Box::new(FixedClock(Timestamp::from_unix(0, 0)))
+}
@@ -1,13 +1,59 @@
 use std::char::{decode_utf16, REPLACEMENT_CHARACTER};
+use std::collections::HashSet;
 use std::ffi::OsStr;
 use std::fmt;
 use std::io;
+use std::mem::size_of;
 use std::str;
 
-use traits::{self, Dir as DirTrait, Entry as EntryTrait};
+use traits::{self, Dir as DirTrait};
 use util::VecExt;
 use vfat::{Attributes, Date, Metadata, Time, Timestamp};
-use vfat::{Cluster, Entry, File, Shared, VFat};
+use vfat::{short_name, Cluster, Entry, File, Shared, VFat};
+
+/// The location of a directory entry on disk.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct EntryLocation {
+    /// The first cluster of the directory containing the entry.
+    pub dir: Cluster,
+    /// The index of the entry's first LFN entry in the directory, or of its
+    /// regular entry if it has no long file name.
+    pub first: usize,
+    /// The index of the entry's regular entry in the directory.
+    pub slot: usize,
+}
+
+impl EntryLocation {
+    /// Reads the regular entry at `self`, applies `f` to it, writes it back,
+    /// and returns it.
+    pub(crate) fn update<F>(&self, vfat: &mut VFat, f: F) -> io::Result<VFatRegularDirEntry>
+    where
+        F: FnOnce(&mut VFatRegularDirEntry),
+    {
+        let mut regular = self.read(vfat)?;
+        f(&mut regular);
+        vfat.write_dir_entry(self.dir, self.slot, &VFatDirEntry { regular })?;
+        Ok(regular)
+    }
+
+    /// Reads the regular entry at `self`.
+    pub(crate) fn read(&self, vfat: &VFat) -> io::Result<VFatRegularDirEntry> {
+        Ok(unsafe { vfat.read_dir_entry(self.dir, self.slot)?.regular })
+    }
+
+    /// Marks the regular entry at `self` and its LFN entries as deleted.
+    /// Handles made from the entry can no longer be used.
+    pub(crate) fn delete(&self, vfat: &mut VFat) -> io::Result<()> {
+        vfat.forget_file(*self);
+        for slot in self.first..self.slot + 1 {
+            let mut entry = vfat.read_dir_entry(self.dir, slot)?;
+            entry.mark_deleted();
+            vfat.write_dir_entry(self.dir, slot, &entry)?;
+        }
+
+        Ok(())
+    }
+}
 
 #[derive(Debug)]
 pub struct Dir {
@@ -35,6 +81,12 @@ impl Dir {
         &self.metadata
     }
 
+    /// The first cluster of this directory's chain.
+    #[cfg(test)]
+    pub(crate) fn start(&self) -> Cluster {
+        self.start
+    }
+
     /// Finds the entry named `name` in `self` and returns it. Comparison is
     /// case-insensitive.
     ///
@@ -51,26 +103,591 @@ impl Dir {
             "name is not valid utf-8",
         ))?;
 
-        let entry = self
-            .entries()?
-            .find(|entry| entry.name().eq_ignore_ascii_case(name.as_ref()))
-            .ok_or(io::Error::new(
-                io::ErrorKind::NotFound,
-                format!("{}: not found", name),
-            ))?;
+        let raw = self.find_raw(name)?;
+        Ok(raw.into_entry(self.vfat.clone()))
+    }
+
+    fn find_raw(&self, name: &str) -> io::Result<RawEntry> {
+        let mut iter = self.entries()?;
+        while let Some(raw) = iter.next_raw()? {
+            if raw.name.eq_ignore_ascii_case(name) {
+                return Ok(raw);
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{}: not found", name),
+        ))
+    }
+
+    /// Like `find_raw`, but reads `self` through `vfat`, a borrow the caller
+    /// holds while it acts on the entry found.
+    fn find_raw_in(&self, vfat: &VFat, name: &str) -> io::Result<RawEntry> {
+        let mut iter = DirIter::read_all(self.vfat.clone(), vfat, self.start)?;
+        while let Some(raw) = iter.next_raw()? {
+            if raw.name.eq_ignore_ascii_case(name) {
+                return Ok(raw);
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{}: not found", name),
+        ))
+    }
+
+    /// Removes the entry named `name` from `self` and frees its clusters. If
+    /// the entry is a directory and `children` is `true`, its contents are
+    /// removed recursively first.
+    ///
+    /// # Errors
+    ///
+    /// If no entry with name `name` exists in `self`, an error of `NotFound` is
+    /// returned.
+    ///
+    /// If `name` is "." or "..", an error of `InvalidInput` is returned.
+    ///
+    /// If the entry is a non-empty directory and `children` is `false`, or
+    /// the entry or, when removing recursively, one of its descendants is a
+    /// file with an open handle, an error of `Other` is returned.
+    pub fn remove(&self, name: &str, children: bool) -> io::Result<()> {
+        if name == "." || name == ".." {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: cannot be removed", name),
+            ));
+        }
+
+        let mut vfat = self.vfat.borrow_mut();
+        let raw = self.find_raw_in(&vfat, name)?;
+        self.remove_raw(&mut vfat, raw, children)
+    }
+
+    /// Sets the attributes of the entry named `name` in `self` to
+    /// `attributes`.
+    ///
+    /// # Errors
+    ///
+    /// If no entry with name `name` exists in `self`, an error of `NotFound` is
+    /// returned.
+    ///
+    /// If `name` is "." or "..", or `attributes` sets the volume ID bit or
+    /// changes the directory bit, an error of `InvalidInput` is returned.
+    pub fn set_attributes(&self, name: &str, attributes: Attributes) -> io::Result<()> {
+        if name == "." || name == ".." {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: attributes cannot be set", name),
+            ));
+        }
+
+        let mut vfat = self.vfat.borrow_mut();
+        let raw = self.find_raw_in(&vfat, name)?;
+        if attributes.volume_id() || attributes.directory() != raw.regular.attributes().directory() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: directory and volume ID attributes cannot be set", name),
+            ));
+        }
+
+        let regular = raw
+            .location
+            .update(&mut vfat, |regular| regular.set_attributes(attributes))?;
+        vfat.set_open_metadata(raw.location, regular.metadata());
+        Ok(())
+    }
+
+    /// Moves the entry named `name` in `self` into the directory `to` under
+    /// the name `to_name`. Only directory entries are rewritten: the entry's
+    /// data stays where it is. If a directory moves to a new parent, its ".."
+    /// entry is updated to point to `to`.
+    ///
+    /// If the entry is a file and a file named `to_name` already exists in
+    /// `to`, that file is replaced: the entry is written over the existing
+    /// file's regular entry, which keeps its name, and the existing file's
+    /// clusters are freed.
+    ///
+    /// # Crash safety
+    ///
+    /// Each step is written to the disk before the next one starts. When
+    /// replacing, the entry named `name` is removed first and the existing
+    /// entry is then overwritten within a single sector, so after a crash
+    /// `to_name` refers to either the old or the new file; at worst the
+    /// clusters of one of them are left allocated but unreferenced.
+    /// Otherwise, the new entry is written before the old one is removed, and
+    /// a crash in between leaves two entries referring to the same clusters.
+    ///
+    /// # Errors
+    ///
+    /// If no entry with name `name` exists in `self`, an error of `NotFound` is
+    /// returned.
+    ///
+    /// If an entry named `to_name` already exists in `to` and either it or
+    /// the entry being moved is a directory, an error of `AlreadyExists` is
+    /// returned.
+    ///
+    /// If the file being replaced has an open handle, an error of `Other` is
+    /// returned.
+    ///
+    /// If `name` is "." or "..", `to_name` is not a valid long file name, or
+    /// the entry is a directory and `to` is that directory or one of its
+    /// descendants, an error of `InvalidInput` is returned.
+    pub fn rename(&self, name: &str, to: &Dir, to_name: &str) -> io::Result<()> {
+        if name == "." || name == ".." {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: cannot be renamed", name),
+            ));
+        }
+
+        // Everything from the lookups to the last write happens under one
+        // borrow, so no other change to either directory can come between.
+        let mut vfat = self.vfat.borrow_mut();
+        let raw = self.find_raw_in(&vfat, name)?;
+        let start = raw.regular.cluster();
+        let directory = raw.regular.attributes().directory();
+        if directory {
+            to.check_outside(&vfat, start)?;
+        }
+
+        let same_dir = self.start == to.start;
+        if same_dir && raw.name == to_name {
+            return Ok(());
+        }
+
+        // A rename that only changes the case of the name must not conflict
+        // with the entry being renamed.
+        let replacing = if same_dir && raw.name.eq_ignore_ascii_case(to_name) {
+            Some(raw.location)
+        } else {
+            None
+        };
+
+        if replacing.is_none() {
+            match to.find_raw_in(&vfat, to_name) {
+                Ok(existing) => return self.replace_raw(&mut vfat, &raw, &existing),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let location = to.insert(&mut vfat, to_name, raw.regular, replacing)?;
+
+        // Open handles move to the new entry first, so that the sync writes
+        // an accessed date they recorded there.
+        vfat.relocate_file(raw.location, location);
+        vfat.sync()?;
+        raw.location.delete(&mut vfat)?;
+
+        if directory && !same_dir {
+            let parent = if to.start == vfat.root_dir_cluster() {
+                Cluster::from(0)
+            } else {
+                to.start
+            };
+
+            let dotdot = EntryLocation {
+                dir: start,
+                first: 1,
+                slot: 1,
+            };
+            dotdot.update(&mut vfat, |regular| regular.set_cluster(parent))?;
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the file `existing` with the file `raw` in `self` as
+    /// described in `rename()`.
+    fn replace_raw(&self, vfat: &mut VFat, raw: &RawEntry, existing: &RawEntry) -> io::Result<()> {
+        if raw.regular.attributes().directory() || existing.regular.attributes().directory() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{}: already exists", existing.name),
+            ));
+        }
+
+        if vfat.is_open(existing.location) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("{}: file is open", existing.name),
+            ));
+        }
+
+        // The source entry is read again once accessed dates recorded by its
+        // open handles are written to it, as it is copied over `existing`.
+        vfat.write_accessed()?;
+        let replacement = raw.location.read(vfat)?;
+        raw.location.delete(vfat)?;
+        vfat.sync()?;
+
+        vfat.forget_file(existing.location);
+        existing.location.update(vfat, |regular| {
+            let short_name = regular.short_name();
+            *regular = replacement;
+            regular.set_short_name(&short_name);
+        })?;
+        vfat.sync()?;
+        vfat.relocate_file(raw.location, existing.location);
+
+        let old = existing.regular.cluster();
+        if old.get() != 0 {
+            vfat.free_chain(old)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns an error of `InvalidInput` if `self` is the directory whose
+    /// chain begins at `dir` or one of its descendants. Ancestors are found by
+    /// following ".." entries up to the root directory.
+    fn check_outside(&self, vfat: &VFat, dir: Cluster) -> io::Result<()> {
+        let root = vfat.root_dir_cluster();
+
+        let mut visited = vec![];
+        let mut current = self.start;
+        while current != root && current.get() != 0 {
+            if current == dir {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "cannot move a directory into itself",
+                ));
+            }
+
+            let dotdot = unsafe { vfat.read_dir_entry(current, 1)?.regular };
+            if !dotdot.dot() || visited.contains(&current) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid directory hierarchy",
+                ));
+            }
+
+            visited.push(current);
+            current = dotdot.cluster();
+        }
+
+        Ok(())
+    }
+
+    /// Removes `raw` and, if `children` is `true`, its contents. Nothing is
+    /// removed unless every entry to be removed can be: the whole subtree is
+    /// listed and checked for open files first, all under the caller's borrow
+    /// of `vfat`, so nothing can be added to it in between.
+    fn remove_raw(&self, vfat: &mut VFat, raw: RawEntry, children: bool) -> io::Result<()> {
+        let mut doomed = vec![];
+        self.list_removal(vfat, raw, children, &mut doomed)?;
+
+        if let Some(open) = doomed.iter().find(|raw| vfat.is_open(raw.location)) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("{}: file is open", open.name),
+            ));
+        }
+
+        for raw in doomed.iter() {
+            raw.location.delete(vfat)?;
+            let start = raw.regular.cluster();
+            if start.get() != 0 {
+                vfat.free_chain(start)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Appends the entries removing `raw` would delete to `doomed`, each
+    /// directory's contents before the directory itself.
+    fn list_removal(
+        &self,
+        vfat: &VFat,
+        raw: RawEntry,
+        children: bool,
+        doomed: &mut Vec<RawEntry>,
+    ) -> io::Result<()> {
+        if raw.regular.attributes().directory() {
+            let mut iter = DirIter::read_all(self.vfat.clone(), vfat, raw.regular.cluster())?;
+            let mut contents = vec![];
+            while let Some(child) = iter.next_raw()? {
+                if !child.regular.dot() {
+                    contents.push(child);
+                }
+            }
+
+            if !contents.is_empty() && !children {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("{}: directory not empty", raw.name),
+                ));
+            }
+
+            for child in contents {
+                self.list_removal(vfat, child, true, doomed)?;
+            }
+        }
+
+        doomed.push(raw);
+        Ok(())
+    }
+
+    /// Creates a new, empty regular file named `name` in `self` and returns
+    /// it.
+    ///
+    /// # Errors
+    ///
+    /// If `name` is not a valid long file name, an error of `InvalidInput` is
+    /// returned.
+    ///
+    /// If an entry named `name` already exists in `self`, an error of
+    /// `AlreadyExists` is returned.
+    pub fn create_file(&self, name: &str) -> io::Result<File> {
+        let (location, regular, generation) = {
+            let mut vfat = self.vfat.borrow_mut();
+            let now = vfat.now();
+            let regular = VFatRegularDirEntry::new(Attributes::from_raw(0x20), Cluster::from(0), 0, now);
+            let location = self.insert(&mut vfat, name, regular, None)?;
+            (location, regular, vfat.generation())
+        };
+
+        let metadata = regular.metadata();
+        let vfat = self.vfat.clone();
+        let mut file = File::new(vfat, regular.cluster(), name.to_string(), metadata, location, generation);
+        file.register()?;
+        Ok(file)
+    }
+
+    /// Creates a new, empty directory named `name` in `self` and returns it.
+    /// The new directory's first cluster is zeroed and holds its "." and ".."
+    /// entries.
+    ///
+    /// # Errors
+    ///
+    /// If `name` is not a valid long file name, an error of `InvalidInput` is
+    /// returned.
+    ///
+    /// If an entry named `name` already exists in `self`, an error of
+    /// `AlreadyExists` is returned.
+    pub fn create_dir(&self, name: &str) -> io::Result<Dir> {
+        let (start, regular) = {
+            let mut vfat = self.vfat.borrow_mut();
+            self.check_insert(&vfat, name, None)?;
+            let now = vfat.now();
+
+            // ".." refers to the root directory as cluster 0.
+            let parent = if self.start == vfat.root_dir_cluster() {
+                Cluster::from(0)
+            } else {
+                self.start
+            };
+
+            // Until the entry is written, nothing refers to the new cluster.
+            let start = vfat.alloc_cluster(None)?;
+            let regular = VFatRegularDirEntry::new(Attributes::from_raw(0x10), start, 0, now);
+            let written = init_dir(&mut vfat, start, parent, now)
+                .and_then(|_| self.insert(&mut vfat, name, regular, None));
+            if let Err(e) = written {
+                let _ = vfat.free_chain(start);
+                return Err(e);
+            }
+
+            (start, regular)
+        };
+
+        let metadata = regular.metadata();
+        let vfat = self.vfat.clone();
+        Ok(Dir::new(vfat, start, name.to_string(), metadata))
+    }
+
+    /// Writes an entry named `name` into `self` and returns its location. The
+    /// short name and LFN entries are generated from `name`; every other field
+    /// is taken from `regular`.
+    ///
+    /// Runs of deleted entries are reused when they are long enough. Otherwise
+    /// the entry is placed at the end of the directory, which is extended
+    /// with zeroed clusters as needed.
+    ///
+    /// The entry at `replacing`, if any, is about to be deleted by the caller
+    /// and is not considered a conflict. The check for conflicts and the
+    /// write both happen under the caller's borrow of `vfat`.
+    fn insert(
+        &self,
+        vfat: &mut VFat,
+        name: &str,
+        mut regular: VFatRegularDirEntry,
+        replacing: Option<EntryLocation>,
+    ) -> io::Result<EntryLocation> {
+        let taken = self.check_insert(vfat, name, replacing)?;
+        let entries = vfat.read_dir(self.start)?;
+
+        let short_name = short_name::generate(name, &taken)?;
+        regular.set_short_name(&short_name);
+
+        let mut slots: Vec<VFatDirEntry> =
+            VFatLfnDirEntry::sequence(name, short_name::checksum(&short_name))
+                .into_iter()
+                .map(|long_filename| VFatDirEntry { long_filename })
+                .collect();
+        slots.push(VFatDirEntry { regular });
+
+        let first = reserve_slots(vfat, self.start, &entries, slots.len())?;
+        for (i, slot) in slots.iter().enumerate() {
+            vfat.write_dir_entry(self.start, first + i, slot)?;
+        }
+
+        Ok(EntryLocation {
+            dir: self.start,
+            first,
+            slot: first + slots.len() - 1,
+        })
+    }
+
+    /// Checks that an entry named `name` can be written into `self`, read
+    /// through `vfat`, and returns the short names already taken there. The
+    /// entry at `replacing`, if any, is skipped as in `insert()`.
+    fn check_insert(
+        &self,
+        vfat: &VFat,
+        name: &str,
+        replacing: Option<EntryLocation>,
+    ) -> io::Result<HashSet<[u8; 11]>> {
+        check_name(name)?;
+
+        let mut taken = HashSet::new();
+        let mut iter = DirIter::read_all(self.vfat.clone(), vfat, self.start)?;
+        while let Some(raw) = iter.next_raw()? {
+            if Some(raw.location) == replacing {
+                continue;
+            }
+
+            let short_matches = raw
+                .regular
+                .name()
+                .map_or(false, |short| short.eq_ignore_ascii_case(name));
+            if raw.name.eq_ignore_ascii_case(name) || short_matches {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{}: already exists", name),
+                ));
+            }
+
+            taken.insert(raw.regular.short_name());
+        }
+
+        Ok(taken)
+    }
+}
+
+/// Zeroes `start`, the first cluster of a new directory, and writes its "."
+/// and ".." entries, pointing to `start` and `parent`.
+fn init_dir(vfat: &mut VFat, start: Cluster, parent: Cluster, now: Timestamp) -> io::Result<()> {
+    vfat.zero_cluster(start)?;
+
+    let dots = [(b".          ", start), (b"..         ", parent)];
+    for (slot, &(short_name, cluster)) in dots.iter().enumerate() {
+        let mut regular = VFatRegularDirEntry::new(Attributes::from_raw(0x10), cluster, 0, now);
+        regular.set_short_name(short_name);
+        vfat.write_dir_entry(start, slot, &VFatDirEntry { regular })?;
+    }
+
+    Ok(())
+}
+
+/// Returns an error of `InvalidInput` if `name` cannot be stored as a long
+/// file name.
+fn check_name(name: &str) -> io::Result<()> {
+    let invalid = name.is_empty()
+        || name.ends_with('.')
+        || name.ends_with(' ')
+        || name.encode_utf16().count() > 255
+        || name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c));
+
+    if invalid {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}: invalid file name", name),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns the index of the first run of `n` consecutive free slots in the
+/// directory starting at cluster `dir`, whose entries are `entries`. If the run
+/// extends past the end of the directory, the directory is extended with
+/// zeroed clusters to hold it.
+pub(crate) fn reserve_slots(
+    vfat: &mut VFat,
+    dir: Cluster,
+    entries: &[VFatDirEntry],
+    n: usize,
+) -> io::Result<usize> {
+    let first = find_free_run(entries, n);
+    let entries_per_cluster = vfat.cluster_size_bytes() / size_of::<VFatDirEntry>();
+    let mut capacity = entries.len();
+    if first + n > capacity {
+        let mut last = *vfat.chain(dir)?.last().ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "directory has no clusters",
+        ))?;
+
+        while first + n > capacity {
+            last = vfat.alloc_cluster(Some(last))?;
+            vfat.zero_cluster(last)?;
+            capacity += entries_per_cluster;
+        }
+    }
+
+    Ok(first)
+}
+
+/// Returns the index and contents of the volume label entry among the root
+/// directory entries `entries`, if there is one.
+pub(crate) fn find_volume_label(entries: &[VFatDirEntry]) -> Option<(usize, VFatRegularDirEntry)> {
+    for (i, entry) in entries.iter().enumerate() {
+        if entry.end() {
+            break;
+        }
 
-        Ok(entry)
+        let entry: VFatEntry = entry.into();
+        match entry.regular() {
+            Some(regular) if !regular.deleted() && regular.attributes().volume_id() => {
+                return Some((i, *regular));
+            }
+            _ => {}
+        }
     }
+
+    None
+}
+
+/// Returns the index of the first run of `n` consecutive free entries in
+/// `entries`. Every entry from the end-of-directory marker on is free, so the
+/// run may extend past the end of `entries`.
+fn find_free_run(entries: &[VFatDirEntry], n: usize) -> usize {
+    let mut start = 0;
+    for (i, entry) in entries.iter().enumerate() {
+        if entry.end() {
+            return start;
+        }
+
+        if !entry.deleted() {
+            start = i + 1;
+        } else if i + 1 - start >= n {
+            return start;
+        }
+    }
+
+    start
 }
 
 #[repr(C, packed)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Default)]
 pub struct VFatRegularDirEntry {
     name: [u8; 8],
     extension: [u8; 3],
     attributes: u8,
     _nt_reserved: u8,
-    _created_time_tenths_second: u8,
+    created_time_tenths_second: u8,
     created_time: u16,
     created_date: u16,
     accessed_date: u16,
@@ -97,22 +714,65 @@ impl fmt::Debug for VFatRegularDirEntry {
 }
 
 impl VFatRegularDirEntry {
-    fn sentinel(&self) -> bool {
-        self.name[0] == 0x00
+    /// Returns an entry created at `now`, which is also its modification and
+    /// access time.
+    pub(crate) fn new(
+        attributes: Attributes,
+        cluster: Cluster,
+        size: u32,
+        now: Timestamp,
+    ) -> VFatRegularDirEntry {
+        let mut entry = VFatRegularDirEntry {
+            attributes: attributes.as_raw(),
+            created_time_tenths_second: now.centiseconds,
+            created_time: now.time.as_raw(),
+            created_date: now.date.as_raw(),
+            size,
+            ..Default::default()
+        };
+        entry.set_cluster(cluster);
+        entry.set_modified(now);
+        entry
+    }
+
+    /// Whether this is a "." or ".." entry.
+    fn dot(&self) -> bool {
+        self.name[0] == b'.'
     }
 
     fn deleted(&self) -> bool {
         self.name[0] == 0x05 || self.name[0] == 0xE5
     }
 
-    fn cluster(&self) -> Cluster {
+    pub(crate) fn cluster(&self) -> Cluster {
         Cluster::from(((self.cluster_high as u32) << 16) | (self.cluster_low as u32))
     }
 
+    pub(crate) fn set_cluster(&mut self, cluster: Cluster) {
+        self.cluster_high = (cluster.get() >> 16) as u16;
+        self.cluster_low = cluster.get() as u16;
+    }
+
+    /// The raw, space-padded 8.3 name: eight bytes of name followed by three
+    /// bytes of extension.
+    pub(crate) fn short_name(&self) -> [u8; 11] {
+        let mut short_name = [0; 11];
+        short_name[..8].copy_from_slice(&self.name);
+        short_name[8..].copy_from_slice(&self.extension);
+        short_name
+    }
+
+    pub(crate) fn set_short_name(&mut self, short_name: &[u8; 11]) {
+        self.name.copy_from_slice(&short_name[..8]);
+        self.extension.copy_from_slice(&short_name[8..]);
+    }
+
     fn created(&self) -> Timestamp {
         let date = Date::from_raw(self.created_date);
         let time = Time::from_raw(self.created_time);
-        Timestamp::new(date, time)
+        let mut created = Timestamp::new(date, time);
+        created.centiseconds = self.created_time_tenths_second;
+        created
     }
 
     fn accessed(&self) -> Timestamp {
@@ -120,21 +780,50 @@ impl VFatRegularDirEntry {
         Timestamp::new(date, Default::default())
     }
 
+    /// Sets the access date to the date of `now`.
+    pub(crate) fn set_accessed(&mut self, now: Timestamp) {
+        self.accessed_date = now.date.as_raw();
+    }
+
     fn modified(&self) -> Timestamp {
         let date = Date::from_raw(self.modified_date);
         let time = Time::from_raw(self.modified_time);
         Timestamp::new(date, time)
     }
 
+    /// Sets the modification time to `now`. A modification is also an
+    /// access, so the access date is updated as well.
+    pub(crate) fn set_modified(&mut self, now: Timestamp) {
+        self.modified_date = now.date.as_raw();
+        self.modified_time = now.time.as_raw();
+        self.set_accessed(now);
+    }
+
     fn attributes(&self) -> Attributes {
         Attributes::from_raw(self.attributes)
     }
 
+    fn set_attributes(&mut self, attributes: Attributes) {
+        self.attributes = attributes.as_raw();
+    }
+
+    /// Sets the archive bit, flagging the entry as modified since the last
+    /// backup.
+    pub(crate) fn set_archive(&mut self) {
+        let mut attributes = self.attributes();
+        attributes.set_archive(true);
+        self.set_attributes(attributes);
+    }
+
     fn size(&self) -> u64 {
         self.size as u64
     }
 
-    fn metadata(&self) -> Metadata {
+    pub(crate) fn set_size(&mut self, size: u32) {
+        self.size = size;
+    }
+
+    pub(crate) fn metadata(&self) -> Metadata {
         let attributes = self.attributes();
         let created = self.created();
         let accessed = self.accessed();
@@ -173,8 +862,16 @@ impl VFatRegularDirEntry {
     }
 }
 
+/// The flag set in the sequence number of the last LFN entry of a long file
+/// name, which is stored first.
+const LAST_LFN_ENTRY: u8 = 0x40;
+
+/// The maximum number of LFN entries making up a long file name of 255 UTF-16
+/// code units.
+const MAX_LFN_ENTRIES: u8 = 20;
+
 #[repr(C, packed)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default)]
 pub struct VFatLfnDirEntry {
     seqno: u8,
     name_1: [u16; 5],
@@ -186,6 +883,44 @@ pub struct VFatLfnDirEntry {
     name_3: [u16; 2],
 }
 
+impl VFatLfnDirEntry {
+    /// Returns the LFN entries that store the long file name `name` for the
+    /// regular entry whose short name checksum is `checksum`, in the order
+    /// they are laid out on disk.
+    pub(crate) fn sequence(name: &str, checksum: u8) -> Vec<VFatLfnDirEntry> {
+        let mut units: Vec<u16> = name.encode_utf16().collect();
+        let count = (units.len() + 12) / 13;
+        if units.len() < count * 13 {
+            units.push(0x0000);
+        }
+        units.resize(count * 13, 0xFFFF);
+
+        let mut entries: Vec<VFatLfnDirEntry> = units
+            .chunks(13)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let (mut name_1, mut name_2, mut name_3) = ([0; 5], [0; 6], [0; 2]);
+                name_1.copy_from_slice(&chunk[..5]);
+                name_2.copy_from_slice(&chunk[5..11]);
+                name_3.copy_from_slice(&chunk[11..]);
+
+                let last = if i + 1 == count { LAST_LFN_ENTRY } else { 0x00 };
+                VFatLfnDirEntry {
+                    seqno: (i + 1) as u8 | last,
+                    name_1,
+                    attributes: 0x0F,
+                    dos_checksum: checksum,
+                    name_2,
+                    name_3,
+                    ..Default::default()
+                }
+            }).collect();
+
+        entries.reverse();
+        entries
+    }
+}
+
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct VFatUnknownDirEntry {
@@ -194,12 +929,35 @@ pub struct VFatUnknownDirEntry {
     _unknown_2: [u8; 20],
 }
 
+#[derive(Copy, Clone)]
 pub union VFatDirEntry {
     unknown: VFatUnknownDirEntry,
     regular: VFatRegularDirEntry,
     long_filename: VFatLfnDirEntry,
 }
 
+impl VFatDirEntry {
+    /// Whether this is the end-of-directory marker.
+    fn end(&self) -> bool {
+        unsafe { self.unknown._unknown_1[0] == 0x00 }
+    }
+
+    /// Whether this entry, regular or LFN, has been deleted.
+    fn deleted(&self) -> bool {
+        unsafe { self.unknown._unknown_1[0] == 0xE5 }
+    }
+
+    fn mark_deleted(&mut self) {
+        unsafe { self.unknown._unknown_1[0] = 0xE5 }
+    }
+}
+
+impl From<VFatRegularDirEntry> for VFatDirEntry {
+    fn from(regular: VFatRegularDirEntry) -> VFatDirEntry {
+        VFatDirEntry { regular }
+    }
+}
+
 impl From<VFatRegularDirEntry> for VFatEntry {
     fn from(regular: VFatRegularDirEntry) -> VFatEntry {
         VFatEntry::Regular(regular)
@@ -240,14 +998,6 @@ impl VFatEntry {
             None
         }
     }
-
-    fn lfn(&self) -> Option<&VFatLfnDirEntry> {
-        if let &VFatEntry::Lfn(ref lfn) = self {
-            Some(lfn)
-        } else {
-            None
-        }
-    }
 }
 
 impl traits::Dir for Dir {
@@ -259,117 +1009,262 @@ impl traits::Dir for Dir {
 
     /// Returns an interator over the entries in this directory.
     fn entries(&self) -> io::Result<Self::Iter> {
-        let mut vfat = self.vfat.borrow_mut();
-        let mut buf = vec![];
+        DirIter::new(self.vfat.clone(), self.start)
+    }
+}
 
-        vfat.read_chain(self.start, &mut buf, None)?;
+/// A regular directory entry along with its resolved name and its location.
+struct RawEntry {
+    regular: VFatRegularDirEntry,
+    name: String,
+    location: EntryLocation,
+    /// The generation of the table of open files when the entry was read.
+    generation: u64,
+}
 
-        let buf = unsafe { buf.cast::<VFatDirEntry>() };
+impl RawEntry {
+    fn into_entry(self, vfat: Shared<VFat>) -> Entry {
+        let metadata = self.regular.metadata();
+        let start = self.regular.cluster();
+        let location = self.location;
 
-        Ok(DirIter::new(self.vfat.clone(), buf))
+        if metadata.attributes.directory() {
+            Entry::Dir(Dir::new(vfat, start, self.name, metadata))
+        } else {
+            Entry::File(File::new(vfat, start, self.name, metadata, location, self.generation))
+        }
     }
 }
 
 pub struct DirIter {
     vfat: Shared<VFat>,
-    buf: Vec<VFatDirEntry>,
+    dir: Cluster,
+    /// The next cluster of the directory to read, if any.
+    next_cluster: Option<Cluster>,
+    /// The entries of the cluster being read.
+    entries: Vec<VFatDirEntry>,
+    /// The index in the directory of the first entry in `entries`.
+    base: usize,
+    /// The index in `entries` of the next entry to read.
     current: usize,
+    /// The LFN entries read since the last regular or deleted entry along
+    /// with their indices in the directory.
+    lfns: Vec<(usize, VFatLfnDirEntry)>,
+    /// Whether the end of the directory has been reached or reading it has
+    /// failed.
+    done: bool,
+    /// The error that ended iteration through `Iterator::next()`, if any.
+    error: Option<io::Error>,
+    /// The generation of the table of open files when `entries` were read.
+    generation: u64,
 }
 
 impl DirIter {
-    fn new(vfat: Shared<VFat>, buf: Vec<VFatDirEntry>) -> DirIter {
-        DirIter {
+    /// Returns an iterator over the directory starting at cluster `dir`. The
+    /// first cluster of the directory is read eagerly; the rest are read as
+    /// the iterator reaches them.
+    fn new(vfat: Shared<VFat>, dir: Cluster) -> io::Result<DirIter> {
+        let mut iter = DirIter {
             vfat,
-            buf,
+            dir,
+            next_cluster: Some(dir),
+            entries: vec![],
+            base: 0,
             current: 0,
-        }
+            lfns: vec![],
+            done: false,
+            error: None,
+            generation: 0,
+        };
+
+        iter.read_next_cluster()?;
+        Ok(iter)
     }
 
-    fn name_from_lfn(&self, lfn_start: usize, lfn_stop: usize) -> Option<String> {
-        let mut entries: Vec<VFatLfnDirEntry> = (&self.buf[lfn_start..lfn_stop])
-            .iter()
-            .rev()
-            .map(|entry| entry.into())
-            // first ensure that we stop at the preceding regular in the array
-            .take_while(|entry| {
-                if let &VFatEntry::Lfn(_) = entry {
-                    true
-                } else {
-                    false
-                }
-            }).filter_map(|entry| match entry.lfn() {
-                Some(lfn) if lfn.seqno != 0xE5 => Some(*lfn),
-                _ => None,
-            }).collect();
+    /// Returns an iterator over the directory starting at cluster `dir`, with
+    /// every cluster read up front through `vfat`. Used while the caller holds
+    /// a borrow of the file system, which `new()` would otherwise take again.
+    fn read_all(shared: Shared<VFat>, vfat: &VFat, dir: Cluster) -> io::Result<DirIter> {
+        Ok(DirIter {
+            vfat: shared,
+            dir,
+            next_cluster: None,
+            entries: vfat.read_dir(dir)?,
+            base: 0,
+            current: 0,
+            lfns: vec![],
+            done: false,
+            error: None,
+            generation: vfat.generation(),
+        })
+    }
 
-        entries.sort_by_key(|lfn| lfn.seqno);
+    /// Reads the next cluster of the directory into `self.entries`. Returns
+    /// `false` if the chain has no more clusters.
+    fn read_next_cluster(&mut self) -> io::Result<bool> {
+        let cluster = match self.next_cluster {
+            Some(cluster) => cluster,
+            None => return Ok(false),
+        };
 
-        let mut name: Vec<u16> = vec![];
-        for &lfn in entries.iter() {
-            name.extend(lfn.name_1.iter());
-            name.extend(lfn.name_2.iter());
-            name.extend(lfn.name_3.iter());
+        let vfat = self.vfat.borrow();
+        let mut buf = vec![0; vfat.cluster_size_bytes()];
+        vfat.read_dir_cluster(cluster, &mut buf)?;
+        self.next_cluster = vfat.next_cluster(cluster)?;
+        self.generation = vfat.generation();
+
+        self.base += self.entries.len();
+        self.entries = unsafe { buf.cast::<VFatDirEntry>() };
+        self.current = 0;
+        Ok(true)
+    }
+
+    /// Returns the next entry in the directory along with its index, reading
+    /// the next cluster once the current one is exhausted.
+    fn next_entry(&mut self) -> io::Result<Option<(usize, VFatDirEntry)>> {
+        if self.current == self.entries.len() && !self.read_next_cluster()? {
+            return Ok(None);
         }
 
-        let end = name
-            .iter()
-            .position(|&c| c == 0x0000u16)
-            .unwrap_or(name.len());
+        let entry = self.entries[self.current];
+        self.current += 1;
+        Ok(Some((self.base + self.current - 1, entry)))
+    }
 
-        let s = decode_utf16((&name[..end]).iter().cloned())
-            .map(|c| c.unwrap_or(REPLACEMENT_CHARACTER))
-            .collect::<String>();
+    /// Returns the index of the first of the LFN entries belonging to the
+    /// regular entry `regular` along with the long file name they store.
+    ///
+    /// Returns `None` unless the LFN entries immediately preceding `regular`
+    /// form a valid LFN sequence: sequence numbers counting down contiguously
+    /// to 1 from an entry marked as the last, each carrying the checksum of
+    /// `regular`'s short name. Orphaned LFN entries thus never attach to
+    /// `regular`.
+    fn lfn(&self, regular: &VFatRegularDirEntry) -> Option<(usize, String)> {
+        let checksum = short_name::checksum(&regular.short_name());
 
-        if s.is_empty() {
-            None
-        } else {
-            Some(s)
+        let mut name: Vec<u16> = vec![];
+        for (seqno, &(index, lfn)) in (1..MAX_LFN_ENTRIES + 1).zip(self.lfns.iter().rev()) {
+            if lfn.seqno & !LAST_LFN_ENTRY != seqno || lfn.dos_checksum != checksum {
+                return None;
+            }
+
+            name.extend({ lfn.name_1 }.iter());
+            name.extend({ lfn.name_2 }.iter());
+            name.extend({ lfn.name_3 }.iter());
+
+            if lfn.seqno & LAST_LFN_ENTRY != 0 {
+                let end = name
+                    .iter()
+                    .position(|&c| c == 0x0000u16)
+                    .unwrap_or(name.len());
+
+                let s = decode_utf16((&name[..end]).iter().cloned())
+                    .map(|c| c.unwrap_or(REPLACEMENT_CHARACTER))
+                    .collect::<String>();
+
+                return if s.is_empty() { None } else { Some((index, s)) };
+            }
         }
+
+        None
     }
 }
 
-impl Iterator for DirIter {
-    type Item = Entry;
+impl DirIter {
+    /// Returns the next entry in the directory, or `None` at its end.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a cluster of the directory cannot be read or its
+    /// cluster chain is invalid. Iteration ends after an error.
+    pub fn try_next(&mut self) -> io::Result<Option<Entry>> {
+        let raw = self.next_raw()?;
+        Ok(raw.map(|raw| raw.into_entry(self.vfat.clone())))
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.current >= self.buf.len() {
-            return None;
-        }
+    /// Returns the error that ended iteration through `Iterator::next()`, if
+    /// any, so that a listing cut short can be told from a complete one.
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
 
-        let (regular_index, regular, name) = (&self.buf)[self.current..]
-            .iter()
-            .enumerate()
-            .filter_map(|(i, union_entry)| {
-                let index = self.current + i;
-                let entry: VFatEntry = union_entry.into();
-                let regular = entry.regular()?;
-                if !regular.deleted() && !regular.sentinel() {
-                    Some((index, *regular))
-                } else {
-                    None
+    /// Returns the next regular entry in the directory, stopping at the
+    /// end-of-directory marker.
+    fn next_raw(&mut self) -> io::Result<Option<RawEntry>> {
+        while !self.done {
+            let (index, entry) = match self.next_entry() {
+                Ok(Some(next)) => next,
+                Ok(None) => break,
+                Err(e) => {
+                    self.done = true;
+                    return Err(e);
+                }
+            };
+
+            if entry.end() {
+                break;
+            }
+
+            if entry.deleted() {
+                self.lfns.clear();
+                continue;
+            }
+
+            let regular = match (&entry).into() {
+                VFatEntry::Regular(regular) => regular,
+                VFatEntry::Lfn(lfn) => {
+                    if self.lfns.len() == MAX_LFN_ENTRIES as usize {
+                        self.lfns.remove(0);
+                    }
+                    self.lfns.push((index, lfn));
+                    continue;
                 }
-            }).next()
-            .and_then(|(regular_index, regular)| {
-                let name = if self.current < regular_index {
-                    self.name_from_lfn(self.current, regular_index)
-                } else {
-                    None
-                };
-                let name = name.or_else(|| regular.name())?;
+            };
 
-                Some((regular_index, regular, name))
-            })?;
+            let lfn = self.lfn(&regular);
+            self.lfns.clear();
+            if regular.deleted() || regular.attributes().volume_id() {
+                continue;
+            }
 
-        self.current = regular_index + 1;
+            let (first, name) = match lfn {
+                Some((first, name)) => (first, name),
+                None => match regular.name() {
+                    Some(name) => (index, name),
+                    None => continue,
+                },
+            };
 
-        let metadata = regular.metadata();
-        let start = regular.cluster();
-        let vfat = self.vfat.clone();
+            return Ok(Some(RawEntry {
+                regular,
+                name,
+                location: EntryLocation {
+                    dir: self.dir,
+                    first,
+                    slot: index,
+                },
+                generation: self.generation,
+            }));
+        }
 
-        if metadata.attributes.directory() {
-            Some(Entry::Dir(Dir::new(vfat, start, name, metadata)))
-        } else {
-            Some(Entry::File(File::new(vfat, start, name, metadata)))
+        self.done = true;
+        Ok(None)
+    }
+}
+
+impl Iterator for DirIter {
+    type Item = Entry;
+
+    /// Returns the next entry in the directory. If a cluster of the directory
+    /// cannot be read, iteration ends and the error is kept for
+    /// `take_error()`; use `try_next()` to observe errors directly.
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.try_next() {
+            Ok(entry) => entry,
+            Err(e) => {
+                self.error = Some(e);
+                None
+            }
         }
     }
 }
@@ -69,6 +69,18 @@ impl BiosParameterBlock {
         self.reserved_sectors as u64 + self.fats as u64 * self.sectors_per_fat as u64
     }
 
+    /// Whether FAT mirroring is disabled, in which case only the FAT selected
+    /// by `active_fat()` is in use.
+    pub fn mirroring_disabled(&self) -> bool {
+        self.flags & 0x80 != 0
+    }
+
+    /// The zero-based number of the active FAT. Only meaningful when
+    /// mirroring is disabled.
+    pub fn active_fat(&self) -> u8 {
+        (self.flags & 0x0F) as u8
+    }
+
     /// Reads the FAT32 extended BIOS parameter block from sector `sector` of
     /// device `device`.
     ///
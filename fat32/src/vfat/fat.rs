@@ -24,6 +24,12 @@ pub enum Status {
 pub struct FatEntry(pub u32);
 
 impl FatEntry {
+    /// A FAT entry marking a free cluster.
+    pub const FREE: FatEntry = FatEntry(0);
+
+    /// A FAT entry marking the last cluster in a chain.
+    pub const EOC: FatEntry = FatEntry(0x0FFFFFFF);
+
     /// Returns the `Status` of the FAT entry `self`.
     pub fn status(&self) -> Status {
         let cluster = Cluster::from(self.0);
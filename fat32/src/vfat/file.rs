@@ -1,39 +1,233 @@
+use std::cmp::{max, min};
 use std::io::{self, SeekFrom};
 
 use traits;
-use util::align_down;
-use vfat::{Cluster, Metadata, Shared, VFat};
+use vfat::{Cluster, EntryLocation, Metadata, OpenFile, OpenFiles, Shared, VFat};
 
+/// A handle to a file. Handles to the same file share its size, clusters and
+/// metadata through the file system's table of open files, and a file cannot
+/// be removed while it has a handle in the table.
+///
+/// A handle joins the table when the file is opened with `open_file()` or
+/// created, or else on its first read, write or lock, so listing a directory
+/// does not keep its files open.
 #[derive(Debug)]
 pub struct File {
     vfat: Shared<VFat>,
-    start: Cluster,
     name: String,
+    /// The state shared with every other handle to the file. Until the handle
+    /// joins the table, the state as of the directory entry it was made from.
+    open: Shared<OpenFile>,
+    /// The table of open files and this handle's identifier in it, once the
+    /// handle has joined it.
+    registration: Option<(Shared<OpenFiles>, usize)>,
+    /// The generation of the table of open files when the directory entry
+    /// this handle was made from was read. The handle cannot join the table
+    /// if the entry has been deleted or overwritten since.
+    generation: u64,
+    /// The file's metadata as of the last access through this handle.
     metadata: Metadata,
     pos: usize,
 }
 
 impl File {
-    pub fn new(vfat: Shared<VFat>, start: Cluster, name: String, metadata: Metadata) -> File {
+    pub fn new(
+        vfat: Shared<VFat>,
+        start: Cluster,
+        name: String,
+        metadata: Metadata,
+        location: EntryLocation,
+        generation: u64,
+    ) -> File {
         File {
             vfat,
-            start,
             name,
+            open: Shared::new(OpenFile::new(location, start, metadata.clone())),
+            registration: None,
+            generation,
             metadata,
             pos: 0,
         }
     }
 
+    /// Adds this handle to the table of open files, if it is not in it yet.
+    /// If the file had no other open handle, its directory entry is read
+    /// again, as it may have changed since this handle was made.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `NotFound` if the file has been removed since this
+    /// handle was made.
+    pub(crate) fn register(&mut self) -> io::Result<()> {
+        if self.registration.is_some() {
+            return Ok(());
+        }
+
+        let vfat = self.vfat.borrow();
+        let table = vfat.open_files();
+        let (open, handle) = {
+            let mut files = table.borrow_mut();
+            let location = self.open.borrow().location;
+            if files.removed_since(location, self.generation) {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{}: file was removed", self.name),
+                ));
+            }
+
+            if files.is_open(location) {
+                files.open(location, Cluster::from(0), Metadata::default())
+            } else {
+                let regular = location.read(&vfat)?;
+                files.open(location, regular.cluster(), regular.metadata())
+            }
+        };
+
+        self.metadata = open.borrow().metadata.clone();
+        self.open = open;
+        self.registration = Some((table, handle));
+        Ok(())
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
 
+    /// Returns the file's metadata as of the last access through this handle.
+    /// Changes made through other handles since then are not reflected, but
+    /// `size()` always returns the current size once the handle has joined
+    /// the table of open files.
     pub fn metadata(&self) -> &Metadata {
         &self.metadata
     }
 
     fn size(&self) -> u64 {
-        self.metadata.size
+        self.open.borrow().metadata.size
+    }
+
+    /// Takes the advisory exclusive lock on the file for this handle. The lock
+    /// is released by `unlock()` or when the handle is dropped. Locks are
+    /// advisory: they only exclude other handles taking the lock, not their
+    /// reads and writes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `WouldBlock` if another handle holds the lock, and
+    /// an error of `NotFound` if the file has been removed.
+    pub fn try_lock(&mut self) -> io::Result<()> {
+        self.register()?;
+        let handle = self.handle();
+        let mut open = self.open.borrow_mut();
+        match open.locked_by {
+            Some(holder) if holder != handle => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("{}: file is locked", self.name),
+            )),
+            _ => {
+                open.locked_by = Some(handle);
+                Ok(())
+            }
+        }
+    }
+
+    /// Releases the advisory lock on the file if this handle holds it.
+    pub fn unlock(&self) {
+        let mut open = self.open.borrow_mut();
+        if open.locked_by == Some(self.handle()) {
+            open.locked_by = None;
+        }
+    }
+
+    /// Returns `true` if any handle to the file holds its advisory lock.
+    pub fn is_locked(&self) -> bool {
+        match self.registration {
+            Some(_) => self.open.borrow().locked_by.is_some(),
+            None => {
+                let location = self.open.borrow().location;
+                let vfat = self.vfat.borrow();
+                let files = vfat.open_files();
+                let files = files.borrow();
+                !files.removed_since(location, self.generation) && files.is_locked(location)
+            }
+        }
+    }
+
+    /// Returns this handle's identifier in the table of open files, or 0,
+    /// which identifies no handle, if the handle has not joined it.
+    fn handle(&self) -> usize {
+        self.registration.as_ref().map_or(0, |&(_, handle)| handle)
+    }
+
+    /// Truncates or extends the file to `size` bytes, updating its directory
+    /// entry. The position in the file is left unchanged.
+    ///
+    /// Clusters past the new end of the file are freed. When the file grows,
+    /// new clusters are allocated and zeroed, as is the rest of the previous
+    /// last cluster, so that the extension reads as zeroes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidInput` if `size` exceeds the 4GiB FAT32
+    /// limit and an error of `Other` if the file system runs out of free
+    /// clusters.
+    pub fn set_len(&mut self, size: u64) -> io::Result<()> {
+        if size > u32::max_value() as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "file size limit exceeded",
+            ));
+        }
+
+        self.register()?;
+        let mut vfat = self.vfat.borrow_mut();
+        let mut open = self.open.borrow_mut();
+        let cluster_size_bytes = vfat.cluster_size_bytes();
+        let size = size as usize;
+        let old_size = open.metadata.size as usize;
+        let clusters = (size + cluster_size_bytes - 1) / cluster_size_bytes;
+
+        let mut chain = match open.clusters.take() {
+            Some(chain) => chain,
+            None => cluster_map(&vfat, open.start)?,
+        };
+
+        if clusters == 0 {
+            if let Some(&first) = chain.first() {
+                vfat.free_chain(first)?;
+            }
+            chain.clear();
+        } else if chain.len() > clusters {
+            vfat.truncate_chain(chain[clusters - 1])?;
+            chain.truncate(clusters);
+        }
+
+        if size > old_size {
+            let offset = old_size % cluster_size_bytes;
+            if let (true, Some(&last)) = (offset != 0, chain.get(old_size / cluster_size_bytes)) {
+                vfat.write_cluster(last, offset, &vec![0; cluster_size_bytes - offset])?;
+            }
+
+        }
+
+        let allocated = chain.len();
+        let result = grow_chain(&mut vfat, &mut chain, clusters)
+            .and_then(|()| record_chain(&mut vfat, open.location, &chain, size as u32));
+        let metadata = match result {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                release_tail(&mut vfat, &chain, allocated);
+                return Err(e);
+            }
+        };
+
+        open.start = chain.first().cloned().unwrap_or(Cluster::from(0));
+        open.clusters = Some(chain);
+        // A modification is also an access, so no accessed date is pending.
+        open.metadata = metadata;
+        open.access_pending = false;
+        self.metadata = open.metadata.clone();
+
+        Ok(())
     }
 }
 
@@ -41,7 +235,7 @@ impl File {
 impl traits::File for File {
     /// Writes any buffered data to disk.
     fn sync(&mut self) -> io::Result<()> {
-        unimplemented!("File::sync(): read-only filesystem")
+        self.vfat.borrow_mut().sync()
     }
 
     /// Returns the size of the file in bytes.
@@ -50,46 +244,230 @@ impl traits::File for File {
     }
 }
 
+/// Returns the clusters of the file starting at `start`, in order, by walking
+/// the FAT.
+fn cluster_map(vfat: &VFat, start: Cluster) -> io::Result<Vec<Cluster>> {
+    if start.get() == 0 {
+        Ok(vec![])
+    } else {
+        vfat.chain(start)
+    }
+}
+
+/// Allocates zeroed clusters at the end of `chain` until it has `clusters`
+/// clusters.
+fn grow_chain(vfat: &mut VFat, chain: &mut Vec<Cluster>, clusters: usize) -> io::Result<()> {
+    while chain.len() < clusters {
+        let cluster = vfat.alloc_cluster(chain.last().cloned())?;
+        chain.push(cluster);
+        vfat.zero_cluster(cluster)?;
+    }
+
+    Ok(())
+}
+
+/// Writes `buf` at byte `pos` of the file made of `chain`, first allocating
+/// clusters at the end of `chain` until it covers the write.
+fn write_chain(vfat: &mut VFat, chain: &mut Vec<Cluster>, pos: usize, buf: &[u8]) -> io::Result<()> {
+    let cluster_size_bytes = vfat.cluster_size_bytes();
+    while chain.len() * cluster_size_bytes < pos + buf.len() {
+        let cluster = vfat.alloc_cluster(chain.last().cloned())?;
+        chain.push(cluster);
+    }
+
+    let mut written = 0;
+    while written < buf.len() {
+        let pos = pos + written;
+        let offset = pos % cluster_size_bytes;
+        let n = min(cluster_size_bytes - offset, buf.len() - written);
+        vfat.write_cluster(chain[pos / cluster_size_bytes], offset, &buf[written..written + n])?;
+        written += n;
+    }
+
+    Ok(())
+}
+
+/// Records `chain` and `size` as the clusters and size of the file whose
+/// directory entry is at `location`, marks the file modified, and returns its
+/// new metadata.
+fn record_chain(
+    vfat: &mut VFat,
+    location: EntryLocation,
+    chain: &[Cluster],
+    size: u32,
+) -> io::Result<Metadata> {
+    let start = chain.first().cloned().unwrap_or(Cluster::from(0));
+    let now = vfat.now();
+    let regular = location.update(vfat, |regular| {
+        regular.set_cluster(start);
+        regular.set_size(size);
+        regular.set_modified(now);
+        regular.set_archive();
+    })?;
+
+    Ok(regular.metadata())
+}
+
+/// Frees the clusters of `chain` past its first `len`, which were allocated
+/// by a change that failed before the file's directory entry recorded them.
+/// Errors are ignored in favor of the one that caused the change to fail.
+fn release_tail(vfat: &mut VFat, chain: &[Cluster], len: usize) {
+    if chain.len() > len {
+        let _ = match len {
+            0 => vfat.free_chain(chain[0]),
+            len => vfat.truncate_chain(chain[len - 1]),
+        };
+    }
+}
+
 impl io::Read for File {
+    /// Reads from the current position into `buf`. The file's cluster map is
+    /// built by walking the FAT on the first access and cached, so any
+    /// position maps to its cluster directly, whether or not the file is
+    /// fragmented.
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        if self.pos == self.size() as usize {
-            return Ok(0);
-        }
+        // The data is read under a shared borrow, so other handles can read
+        // at the same time. The accessed date is only recorded in the open
+        // file and written to the directory entry by the next sync.
+        self.register()?;
+        let read = {
+            let vfat = self.vfat.borrow();
+            let mut open = self.open.borrow_mut();
+            self.metadata = open.metadata.clone();
 
-        let mut vfat = self.vfat.borrow_mut();
-        let cluster_size_bytes = vfat.cluster_size_bytes();
-        let pos_within_cluster = self.pos - align_down(self.pos, cluster_size_bytes);
-        let file_end_relative = self.size() as usize - self.pos;
-        let read_start_relative = Cluster::from((self.pos / cluster_size_bytes) as u32);
+            let size = open.metadata.size as usize;
+            if self.pos >= size || buf.is_empty() {
+                return Ok(0);
+            }
+
+            if open.clusters.is_none() {
+                open.clusters = Some(cluster_map(&vfat, open.start)?);
+            }
+            let clusters = open.clusters.as_ref().expect("cluster map is cached");
 
-        let max = buf.len();
-        let mut inner_buf = vec![];
-        let mut n = vfat.read_chain(self.start + read_start_relative, &mut inner_buf, Some(max))?;
+            let cluster_size_bytes = vfat.cluster_size_bytes();
+            let end = min(self.pos + buf.len(), size);
+            let mut read = 0;
+            while self.pos + read < end {
+                let pos = self.pos + read;
+                let index = pos / cluster_size_bytes;
+                let offset = pos % cluster_size_bytes;
+                let cluster = *clusters.get(index).ok_or(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "file size exceeds its cluster chain",
+                ))?;
 
-        n -= pos_within_cluster;
-        n = *vec![n, max, file_end_relative].iter().min().unwrap();
-        self.pos += n;
+                // Whole clusters bypass the cache and are read straight into
+                // `buf`, each run of contiguous clusters with a single read.
+                let whole_clusters = (end - pos) / cluster_size_bytes;
+                if offset == 0 && whole_clusters > 0 {
+                    let run = 1 + (1..min(whole_clusters, clusters.len() - index))
+                        .take_while(|&i| clusters[index + i].get() == cluster.get() + i as u32)
+                        .count();
+                    let n = run * cluster_size_bytes;
+                    vfat.read_clusters_direct(cluster, run, &mut buf[read..read + n])?;
+                    read += n;
+                    continue;
+                }
 
-        buf[..n].copy_from_slice(&inner_buf[pos_within_cluster..pos_within_cluster + n]);
-        Ok(n)
+                let n = min(cluster_size_bytes - offset, end - pos);
+                vfat.read_cluster(cluster, offset, &mut buf[read..read + n])?;
+                read += n;
+            }
+
+            open.access(vfat.now());
+            self.metadata = open.metadata.clone();
+            read
+        };
+
+        self.pos += read;
+        Ok(read)
     }
 }
 
 impl io::Write for File {
-    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
-        unimplemented!("File::Write: read-only filesystem")
+    /// Writes `buf` at the current position, overwriting existing data in
+    /// place and allocating and linking new clusters as the file grows. If the
+    /// position is past the end of the file, the gap is first filled with
+    /// zeroes. The file's size and start cluster are updated in its directory
+    /// entry.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidInput` if the write would grow the file
+    /// past the 4GiB FAT32 limit and an error of `Other` if the file system
+    /// runs out of free clusters.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let end = self.pos + buf.len();
+        if end as u64 > u32::max_value() as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "file size limit exceeded",
+            ));
+        }
+
+        self.register()?;
+        if self.pos as u64 > self.size() {
+            let pos = self.pos as u64;
+            self.set_len(pos)?;
+        }
+
+        let mut vfat = self.vfat.borrow_mut();
+        let mut open = self.open.borrow_mut();
+        let mut chain = match open.clusters.take() {
+            Some(chain) => chain,
+            None => cluster_map(&vfat, open.start)?,
+        };
+
+        let allocated = chain.len();
+        let size = max(end as u64, open.metadata.size) as u32;
+        let result = write_chain(&mut vfat, &mut chain, self.pos, buf)
+            .and_then(|()| record_chain(&mut vfat, open.location, &chain, size));
+        let metadata = match result {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                release_tail(&mut vfat, &chain, allocated);
+                return Err(e);
+            }
+        };
+
+        self.pos = end;
+        open.start = chain[0];
+        open.clusters = Some(chain);
+        // A modification is also an access, so no accessed date is pending.
+        open.metadata = metadata;
+        open.access_pending = false;
+        self.metadata = open.metadata.clone();
+
+        Ok(buf.len())
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        unimplemented!("File::flush(): read-only filesystem")
+        self.vfat.borrow_mut().sync()
+    }
+}
+
+impl Drop for File {
+    /// Closes the handle, releasing the file's advisory lock if the handle
+    /// holds it. The file system itself is not borrowed, so a handle can be
+    /// dropped while it is.
+    fn drop(&mut self) {
+        if let Some((ref table, handle)) = self.registration {
+            table.borrow_mut().close(&self.open, handle);
+        }
     }
 }
 
 impl io::Seek for File {
     /// Seek to offset `pos` in the file.
     ///
-    /// A seek to the end of the file is allowed. A seek _beyond_ the end of the
-    /// file returns an `InvalidInput` error.
+    /// A seek to or _beyond_ the end of the file is allowed. Reads past the
+    /// end of the file return no data, and a write past the end of the file
+    /// fills the gap with zeroes.
     ///
     /// If the seek operation completes successfully, this method returns the
     /// new position from the start of the stream. That position can be used
@@ -97,8 +475,7 @@ impl io::Seek for File {
     ///
     /// # Errors
     ///
-    /// Seeking before the start of a file or beyond the end of the file results
-    /// in an `InvalidInput` error.
+    /// Seeking before the start of a file results in an `InvalidInput` error.
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
         let pos = match pos {
             SeekFrom::Start(pos) => pos as i64,
@@ -106,7 +483,7 @@ impl io::Seek for File {
             SeekFrom::Current(pos) => self.pos as i64 + pos,
         };
 
-        if pos < 0 || pos >= self.size() as i64 {
+        if pos < 0 {
             return Err(io::ErrorKind::InvalidInput.into());
         }
 
@@ -0,0 +1,96 @@
+use std::fmt;
+use std::mem::transmute;
+
+use traits::BlockDevice;
+use vfat::Error;
+
+const LEAD_SIGNATURE: u32 = 0x41615252;
+const STRUCT_SIGNATURE: u32 = 0x61417272;
+const TRAIL_SIGNATURE: u32 = 0xAA550000;
+
+/// The value of `free_count` or `next_free` when it is unknown.
+const UNKNOWN: u32 = 0xFFFFFFFF;
+
+/// The FAT32 file system information (FSInfo) sector.
+#[repr(C, packed)]
+pub struct FsInfo {
+    pub lead_signature: u32,
+    pub _reserved_1: [u8; 480],
+    pub struct_signature: u32,
+    pub free_count: u32,
+    pub next_free: u32,
+    pub _reserved_2: [u8; 12],
+    pub trail_signature: u32,
+}
+
+impl FsInfo {
+    fn check_signature(&self) -> Result<(), Error> {
+        if self.lead_signature != LEAD_SIGNATURE
+            || self.struct_signature != STRUCT_SIGNATURE
+            || self.trail_signature != TRAIL_SIGNATURE
+        {
+            Err(Error::BadSignature)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Reads the FSInfo structure from logical sector `sector` of `device`.
+    ///
+    /// # Errors
+    ///
+    /// If the lead, struct, or trail signature is invalid, returns an error of
+    /// `BadSignature`.
+    pub fn from<T: BlockDevice>(mut device: T, sector: u64) -> Result<FsInfo, Error> {
+        let mut buf = vec![0; device.logical_sector_size() as usize];
+        device.read_sector(sector, &mut buf)?;
+
+        let mut raw: [u8; 512] = [0; 512];
+        raw.copy_from_slice(&buf[..512]);
+        let fs_info = unsafe { transmute::<[u8; 512], FsInfo>(raw) };
+
+        fs_info.check_signature()?;
+        Ok(fs_info)
+    }
+
+    /// The last known number of free clusters, if known.
+    pub fn free_count(&self) -> Option<u32> {
+        match self.free_count {
+            UNKNOWN => None,
+            n => Some(n),
+        }
+    }
+
+    pub fn set_free_count(&mut self, free_count: Option<u32>) {
+        self.free_count = free_count.unwrap_or(UNKNOWN);
+    }
+
+    /// The cluster at which to start looking for free clusters, if known.
+    pub fn next_free(&self) -> Option<u32> {
+        match self.next_free {
+            UNKNOWN => None,
+            n => Some(n),
+        }
+    }
+
+    pub fn set_next_free(&mut self, next_free: Option<u32>) {
+        self.next_free = next_free.unwrap_or(UNKNOWN);
+    }
+
+    /// The on-disk representation of `self`.
+    pub fn as_bytes(&self) -> &[u8; 512] {
+        unsafe { &*(self as *const FsInfo as *const [u8; 512]) }
+    }
+}
+
+impl fmt::Debug for FsInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FsInfo")
+            .field("lead_signature", &self.lead_signature)
+            .field("struct_signature", &self.struct_signature)
+            .field("free_count", &self.free_count)
+            .field("next_free", &self.next_free)
+            .field("trail_signature", &self.trail_signature)
+            .finish()
+    }
+}
@@ -1,17 +1,98 @@
 use std::fmt;
+#[cfg(not(target_os = "ros"))]
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use traits::{self, Metadata as MetadataTrait};
 
+/// Seconds from the Unix epoch to 1980-01-01 00:00:00, the earliest time a FAT
+/// timestamp can represent.
+const MIN_UNIX_SECONDS: i64 = 315_532_800;
+
+/// Seconds from the Unix epoch to 2107-12-31 23:59:59, the latest second a
+/// FAT timestamp can represent.
+const MAX_UNIX_SECONDS: i64 = 4_354_819_199;
+
+/// Whether `year` is a leap year.
+fn is_leap_year(year: usize) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+/// The number of days in `month` of `year`.
+fn days_in_month(year: usize, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// The conversions between civil dates and days since the Unix epoch are
+// described in http://howardhinnant.github.io/date_algorithms.html. Both are
+// only used for dates from 1970 on.
+
+/// Returns the civil date `days` days after 1970-01-01.
+fn civil_from_days(days: i64) -> (usize, u8, u8) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year as usize, month as u8, day as u8)
+}
+
+/// Returns the number of days from 1970-01-01 to the civil date `year`,
+/// `month`, `day`.
+fn days_from_civil(year: usize, month: u8, day: u8) -> i64 {
+    let (month, day) = (month as i64, day as i64);
+    let year = year as i64 - if month <= 2 { 1 } else { 0 };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let shifted_month = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * shifted_month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
 /// A date as represented in FAT32 on-disk structures.
 #[repr(C, packed)]
 #[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
 pub struct Date(u16);
 
 impl Date {
+    /// Returns the date `day` of `month` in `year`, or `None` if the date is
+    /// invalid or outside of the years 1980 through 2107 that FAT dates can
+    /// represent.
+    pub fn new(year: usize, month: u8, day: u8) -> Option<Date> {
+        if year < 1980 || year > 2107 || month < 1 || month > 12 {
+            return None;
+        }
+
+        if day < 1 || day > days_in_month(year, month) {
+            return None;
+        }
+
+        let raw = ((year - 1980) << 9) as u16 | (month as u16) << 5 | day as u16;
+        Some(Date(raw))
+    }
+
     pub fn from_raw(raw: u16) -> Date {
         Date(raw)
     }
 
+    pub fn as_raw(&self) -> u16 {
+        self.0
+    }
+
     fn year(&self) -> usize {
         (self.0 >> 9) as usize + 1980usize
     }
@@ -43,10 +124,26 @@ impl fmt::Display for Date {
 pub struct Time(u16);
 
 impl Time {
+    /// Returns the time `hour`:`minute`:`second`, or `None` if the time is
+    /// invalid. FAT times have a resolution of two seconds, so an odd `second`
+    /// is rounded down.
+    pub fn new(hour: u8, minute: u8, second: u8) -> Option<Time> {
+        if hour > 23 || minute > 59 || second > 59 {
+            return None;
+        }
+
+        let raw = (hour as u16) << 11 | (minute as u16) << 5 | (second / 2) as u16;
+        Some(Time(raw))
+    }
+
     pub fn from_raw(raw: u16) -> Time {
         Time(raw)
     }
 
+    pub fn as_raw(&self) -> u16 {
+        self.0
+    }
+
     fn hour(&self) -> u8 {
         (self.0 >> 11) as u8
     }
@@ -82,6 +179,10 @@ impl Attributes {
         Attributes(raw)
     }
 
+    pub fn as_raw(&self) -> u8 {
+        self.0
+    }
+
     pub fn read_only(&self) -> bool {
         self.0 & 0x01 != 0
     }
@@ -109,6 +210,30 @@ impl Attributes {
     pub fn lfn(&self) -> bool {
         (self.0 & 0x0F) == 0x0F
     }
+
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.set(0x01, read_only);
+    }
+
+    pub fn set_hidden(&mut self, hidden: bool) {
+        self.set(0x02, hidden);
+    }
+
+    pub fn set_system(&mut self, system: bool) {
+        self.set(0x04, system);
+    }
+
+    pub fn set_archive(&mut self, archive: bool) {
+        self.set(0x20, archive);
+    }
+
+    fn set(&mut self, bit: u8, value: bool) {
+        if value {
+            self.0 |= bit;
+        } else {
+            self.0 &= !bit;
+        }
+    }
 }
 
 /// A structure containing a date and time.
@@ -116,11 +241,111 @@ impl Attributes {
 pub struct Timestamp {
     pub date: Date,
     pub time: Time,
+    /// Hundredths of a second past `time`, in [0, 200) since `time` has a
+    /// resolution of two seconds. Only recorded for creation timestamps.
+    pub centiseconds: u8,
 }
 
 impl Timestamp {
     pub fn new(date: Date, time: Time) -> Timestamp {
-        Timestamp { date, time }
+        Timestamp {
+            date,
+            time,
+            centiseconds: 0,
+        }
+    }
+
+    /// Returns the timestamp for the given calendar components, keeping the
+    /// full 10 millisecond resolution of FAT creation timestamps. Returns
+    /// `None` if any component is invalid or out of range; `centisecond` must
+    /// be less than 100.
+    pub fn from_calendar(
+        year: usize,
+        month: u8,
+        day: u8,
+        hour: u8,
+        minute: u8,
+        second: u8,
+        centisecond: u8,
+    ) -> Option<Timestamp> {
+        if centisecond > 99 {
+            return None;
+        }
+
+        let date = Date::new(year, month, day)?;
+        let time = Time::new(hour, minute, second)?;
+        Some(Timestamp {
+            date,
+            time,
+            centiseconds: (second % 2) * 100 + centisecond,
+        })
+    }
+
+    /// Returns the timestamp `secs` seconds and `nanos` nanoseconds after
+    /// 1970-01-01 00:00:00 in local time, clamped to the range FAT timestamps
+    /// can represent.
+    pub(crate) fn from_unix(secs: i64, nanos: u32) -> Timestamp {
+        let (secs, nanos) = if secs < MIN_UNIX_SECONDS {
+            (MIN_UNIX_SECONDS, 0)
+        } else if secs > MAX_UNIX_SECONDS {
+            (MAX_UNIX_SECONDS, 999_999_999)
+        } else {
+            (secs, nanos)
+        };
+
+        let (year, month, day) = civil_from_days(secs / 86_400);
+        let seconds = secs % 86_400;
+        let (hour, minute, second) = (seconds / 3_600, seconds / 60 % 60, seconds % 60);
+        Timestamp::from_calendar(
+            year,
+            month,
+            day,
+            hour as u8,
+            minute as u8,
+            second as u8,
+            (nanos / 10_000_000) as u8,
+        ).expect("in range timestamp is valid")
+    }
+
+    /// Returns the number of seconds and nanoseconds from 1970-01-01 00:00:00
+    /// in local time to `self`. Out of range fields read from disk are
+    /// clamped to the nearest valid value.
+    pub(crate) fn to_unix(&self) -> (i64, u32) {
+        let year = self.date.year();
+        let month = self.date.month().max(1).min(12);
+        let day = self.date.day().max(1).min(days_in_month(year, month));
+        let days = days_from_civil(year, month, day);
+
+        let (hour, minute) = (self.time.hour().min(23), self.time.minute().min(59));
+        let second = self.time.second().min(58) + self.centiseconds.min(199) / 100;
+        let seconds = hour as i64 * 3_600 + minute as i64 * 60 + second as i64;
+        let nanos = (self.centiseconds.min(199) % 100) as u32 * 10_000_000;
+        (days * 86_400 + seconds, nanos)
+    }
+
+    /// Returns the timestamp of `time` in the time zone `utc_offset` seconds
+    /// east of UTC, clamped to the years 1980 through 2107 that FAT
+    /// timestamps can represent. Time is truncated to 10 milliseconds.
+    #[cfg(not(target_os = "ros"))]
+    pub fn from_system_time(time: SystemTime, utc_offset: i32) -> Timestamp {
+        let (secs, nanos) = match time.duration_since(UNIX_EPOCH) {
+            Ok(since_epoch) => (since_epoch.as_secs() as i64, since_epoch.subsec_nanos()),
+            Err(_) => (0, 0),
+        };
+        Timestamp::from_unix(secs.saturating_add(utc_offset as i64), nanos)
+    }
+
+    /// Returns the `SystemTime` of `self` interpreted in the time zone
+    /// `utc_offset` seconds east of UTC.
+    #[cfg(not(target_os = "ros"))]
+    pub fn to_system_time(&self, utc_offset: i32) -> SystemTime {
+        let (secs, nanos) = self.to_unix();
+        let secs = secs - utc_offset as i64;
+        if secs < 0 {
+            UNIX_EPOCH - Duration::new(-secs as u64, 0) + Duration::new(0, nanos)
+        } else {
+            UNIX_EPOCH + Duration::new(secs as u64, nanos)
+        }
     }
 }
 
@@ -146,7 +371,11 @@ impl traits::Timestamp for Timestamp {
     }
 
     fn second(&self) -> u8 {
-        self.time.second()
+        self.time.second() + self.centiseconds / 100
+    }
+
+    fn centisecond(&self) -> u8 {
+        self.centiseconds % 100
     }
 }
 
@@ -1,4 +1,5 @@
 pub(crate) mod cache;
+pub(crate) mod clock;
 pub(crate) mod cluster;
 pub(crate) mod dir;
 pub(crate) mod ebpb;
@@ -6,19 +7,29 @@ pub(crate) mod entry;
 pub(crate) mod error;
 pub(crate) mod fat;
 pub(crate) mod file;
+pub(crate) mod fsinfo;
 pub(crate) mod metadata;
+pub(crate) mod open_file;
 pub(crate) mod shared;
+pub(crate) mod short_name;
 pub(crate) mod vfat;
 
-pub use self::dir::Dir;
+#[cfg(not(target_os = "ros"))]
+pub use self::clock::SystemClock;
+pub use self::cache::{CacheStats, Region};
+pub use self::clock::{Clock, FixedClock, ManualClock};
+pub use self::dir::{Dir, DirIter};
 pub use self::ebpb::BiosParameterBlock;
 pub use self::entry::Entry;
 pub use self::error::Error;
 pub use self::file::File;
+pub use self::fsinfo::FsInfo;
 pub use self::metadata::{Attributes, Date, Metadata, Time, Timestamp};
 pub use self::shared::Shared;
 pub use self::vfat::VFat;
 
 pub(crate) use self::cache::{CachedDevice, Partition};
 pub(crate) use self::cluster::Cluster;
+pub(crate) use self::dir::EntryLocation;
 pub(crate) use self::fat::{FatEntry, Status};
+pub(crate) use self::open_file::{OpenFile, OpenFiles};
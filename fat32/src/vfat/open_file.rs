@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+
+use vfat::{Cluster, EntryLocation, Metadata, Shared, Timestamp};
+
+/// The state of an open file, shared by every `File` handle to it so that a
+/// change made through one handle is seen by the others.
+#[derive(Debug)]
+pub(crate) struct OpenFile {
+    /// The location of the file's directory entry. It changes when the file
+    /// is renamed.
+    pub(crate) location: EntryLocation,
+    /// The file's first cluster, or 0 if it has none.
+    pub(crate) start: Cluster,
+    /// The file's metadata, as last written to its directory entry except
+    /// for a pending accessed date.
+    pub(crate) metadata: Metadata,
+    /// Whether `metadata.accessed` is yet to be written to the directory
+    /// entry. Reads only record the accessed date here; it is written by the
+    /// next sync of the file system.
+    pub(crate) access_pending: bool,
+    /// The file's clusters, in order, once the FAT has been walked.
+    pub(crate) clusters: Option<Vec<Cluster>>,
+    /// The handle holding the file's advisory exclusive lock, if any.
+    pub(crate) locked_by: Option<usize>,
+}
+
+impl OpenFile {
+    /// Returns the state of the file whose directory entry, at `location`,
+    /// records `start` and `metadata`.
+    pub(crate) fn new(location: EntryLocation, start: Cluster, metadata: Metadata) -> OpenFile {
+        OpenFile {
+            location,
+            start,
+            metadata,
+            access_pending: false,
+            clusters: None,
+            locked_by: None,
+        }
+    }
+
+    /// Records an access to the file at `now`, without writing it to the
+    /// directory entry.
+    pub(crate) fn access(&mut self, now: Timestamp) {
+        if self.metadata.accessed.date != now.date {
+            self.metadata.accessed = Timestamp::new(now.date, Default::default());
+            self.access_pending = true;
+        }
+    }
+
+    /// Sets the file's metadata to `metadata`, as just read from or written to
+    /// its directory entry, keeping a pending accessed date.
+    pub(crate) fn set_metadata(&mut self, mut metadata: Metadata) {
+        if self.access_pending {
+            metadata.accessed = self.metadata.accessed;
+        }
+        self.metadata = metadata;
+    }
+}
+
+/// The files of a `VFat` that have open handles, keyed by the location of
+/// their directory entries.
+#[derive(Debug, Default)]
+pub(crate) struct OpenFiles {
+    /// Each open file's shared state and its number of handles.
+    files: HashMap<EntryLocation, (Shared<OpenFile>, usize)>,
+    /// The pending accessed dates of files closed since the last sync.
+    accessed: HashMap<EntryLocation, Timestamp>,
+    /// The number of directory entries deleted or overwritten so far, which
+    /// serves as the table's generation.
+    removals: u64,
+    /// The generation just after each location's entry was last deleted or
+    /// overwritten.
+    removed: HashMap<EntryLocation, u64>,
+    next_handle: usize,
+}
+
+impl OpenFiles {
+    /// Registers a new handle to the file whose directory entry is at
+    /// `location`, and returns the file's shared state and the handle's
+    /// identifier. `start` and `metadata` are only used if the file was not
+    /// already open.
+    pub(crate) fn open(
+        &mut self,
+        location: EntryLocation,
+        start: Cluster,
+        metadata: Metadata,
+    ) -> (Shared<OpenFile>, usize) {
+        let accessed = &mut self.accessed;
+        let entry = self.files.entry(location).or_insert_with(|| {
+            let mut file = OpenFile::new(location, start, metadata);
+            if let Some(date) = accessed.remove(&location) {
+                file.access(date);
+            }
+            (Shared::new(file), 0)
+        });
+        entry.1 += 1;
+
+        self.next_handle += 1;
+        (entry.0.clone(), self.next_handle)
+    }
+
+    /// Unregisters the handle `handle` to the open file `file`, releasing the
+    /// file's lock if the handle holds it. The file is removed from the table
+    /// when its last handle is closed, keeping its pending accessed date.
+    pub(crate) fn close(&mut self, file: &Shared<OpenFile>, handle: usize) {
+        let (location, accessed) = {
+            let mut file = file.borrow_mut();
+            if file.locked_by == Some(handle) {
+                file.locked_by = None;
+            }
+            let accessed = if file.access_pending {
+                Some(file.metadata.accessed)
+            } else {
+                None
+            };
+            (file.location, accessed)
+        };
+
+        let closed = match self.files.get_mut(&location) {
+            Some(entry) => {
+                entry.1 -= 1;
+                entry.1 == 0
+            }
+            None => false,
+        };
+
+        if closed {
+            self.files.remove(&location);
+            if let Some(accessed) = accessed {
+                self.accessed.insert(location, accessed);
+            }
+        }
+    }
+
+    /// Returns `true` if the file whose directory entry is at `location` has
+    /// an open handle.
+    pub(crate) fn is_open(&self, location: EntryLocation) -> bool {
+        self.files.contains_key(&location)
+    }
+
+    /// Returns `true` if a handle to the file whose directory entry is at
+    /// `location` holds the file's advisory lock.
+    pub(crate) fn is_locked(&self, location: EntryLocation) -> bool {
+        match self.files.get(&location) {
+            Some(entry) => entry.0.borrow().locked_by.is_some(),
+            None => false,
+        }
+    }
+
+    /// Sets the metadata of the open file at `location`, if any, to
+    /// `metadata`, after its directory entry was changed other than through
+    /// one of its handles.
+    pub(crate) fn set_metadata(&mut self, location: EntryLocation, metadata: Metadata) {
+        if let Some(entry) = self.files.get(&location) {
+            entry.0.borrow_mut().set_metadata(metadata);
+        }
+    }
+
+    /// Records that the directory entry of the open file at `from`, if any,
+    /// has moved to `to`.
+    pub(crate) fn relocate(&mut self, from: EntryLocation, to: EntryLocation) {
+        if let Some(entry) = self.files.remove(&from) {
+            entry.0.borrow_mut().location = to;
+            self.files.insert(to, entry);
+        }
+        if let Some(accessed) = self.accessed.remove(&from) {
+            self.accessed.insert(to, accessed);
+        }
+    }
+
+    /// Returns the table's generation, which advances whenever a directory
+    /// entry is deleted or overwritten.
+    pub(crate) fn generation(&self) -> u64 {
+        self.removals
+    }
+
+    /// Returns `true` if the directory entry at `location` has been deleted
+    /// or overwritten since the table was at generation `generation`, so that
+    /// an entry read from there at that generation is no longer the same file.
+    pub(crate) fn removed_since(&self, location: EntryLocation, generation: u64) -> bool {
+        self.removed.get(&location).map_or(false, |&removed| removed > generation)
+    }
+
+    /// Records that the directory entry at `location` is being deleted or
+    /// overwritten, and forgets the pending accessed date of the closed file
+    /// there.
+    pub(crate) fn forget(&mut self, location: EntryLocation) {
+        self.removals += 1;
+        self.removed.insert(location, self.removals);
+        self.accessed.remove(&location);
+    }
+
+    /// Returns the pending accessed dates of all files, open or closed, by the
+    /// location of their directory entries, and marks them written.
+    pub(crate) fn take_accessed(&mut self) -> Vec<(EntryLocation, Timestamp)> {
+        let mut accessed: Vec<_> = self.accessed.drain().collect();
+        for &(ref file, _) in self.files.values() {
+            let mut file = file.borrow_mut();
+            if file.access_pending {
+                file.access_pending = false;
+                accessed.push((file.location, file.metadata.accessed));
+            }
+        }
+
+        accessed
+    }
+}
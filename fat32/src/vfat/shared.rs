@@ -4,7 +4,14 @@ use std::ops::{Deref, DerefMut};
 ///
 /// The inner `T` can be borrowed immutably with `.borrow()` and mutably with
 /// `.borrow_mut()`. The implementation guarantees the usual reference
-/// guarantees.
+/// guarantees. Where threads are available, any number of immutable borrows
+/// may be held at once.
+///
+/// Concurrent immutable borrows of a `Shared<VFat>` do not make its device
+/// I/O concurrent: the sector cache, and the device behind it, are behind a
+/// single lock that is held for each device access, including the direct
+/// reads of whole clusters. Readers only overlap in work done between device
+/// accesses and in copies out of cached sectors.
 #[derive(Debug)]
 pub struct Shared<T>(imp::Inner<T>);
 
@@ -12,7 +19,7 @@ pub struct Shared<T>(imp::Inner<T>);
 mod imp {
     use super::Shared;
     use std::rc::Rc;
-    use std::sync::Mutex;
+    use std::sync::{Mutex, MutexGuard};
 
     pub type Inner<T> = Rc<Mutex<T>>;
 
@@ -20,6 +27,14 @@ mod imp {
         Rc::new(Mutex::new(val))
     }
 
+    pub fn read<T>(inner: &Inner<T>) -> MutexGuard<T> {
+        inner.lock().expect("all okay")
+    }
+
+    pub fn write<T>(inner: &Inner<T>) -> MutexGuard<T> {
+        inner.lock().expect("all okay")
+    }
+
     // Without an enabled MMU/cache, the processor faults on atomic accesses.
     // As such, use an `Rc` instead of an `Arc` when running on ROS until
     // multithreading, the MMU, and caches are enabled.
@@ -29,12 +44,20 @@ mod imp {
 
 #[cfg(not(target_os = "ros"))]
 mod imp {
-    use std::sync::{Arc, Mutex};
+    use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-    pub type Inner<T> = ::std::sync::Arc<::std::sync::Mutex<T>>;
+    pub type Inner<T> = ::std::sync::Arc<::std::sync::RwLock<T>>;
 
     pub fn new<T>(val: T) -> Inner<T> {
-        Arc::new(Mutex::new(val))
+        Arc::new(RwLock::new(val))
+    }
+
+    pub fn read<T>(inner: &Inner<T>) -> RwLockReadGuard<T> {
+        inner.read().expect("all okay")
+    }
+
+    pub fn write<T>(inner: &Inner<T>) -> RwLockWriteGuard<T> {
+        inner.write().expect("all okay")
     }
 }
 
@@ -47,9 +70,10 @@ impl<T> Shared<T> {
     /// Returns an immutable borrow to the inner value.
     ///
     /// If the inner value is presently mutably borrowed, this function blocks
-    /// until that borrow is returned.
+    /// until that borrow is returned. On ROS, where there is a single thread,
+    /// immutable borrows are exclusive as well.
     pub fn borrow<'a>(&'a self) -> impl Deref<Target = T> + 'a {
-        self.0.lock().expect("all okay")
+        imp::read(&self.0)
     }
 
     /// Returns an mutable borrow to the inner value.
@@ -57,7 +81,7 @@ impl<T> Shared<T> {
     /// If the inner value is presently borrowed, mutably or immutably, this
     /// function blocks until all borrows are returned.
     pub fn borrow_mut<'a>(&'a self) -> impl DerefMut<Target = T> + 'a {
-        self.0.lock().expect("all okay")
+        imp::write(&self.0)
     }
 }
 
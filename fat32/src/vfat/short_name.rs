@@ -0,0 +1,130 @@
+use std::cmp::min;
+use std::collections::HashSet;
+use std::io;
+
+/// The number of numeric tails (`~1` through `~4`) tried before switching to
+/// hashed tails.
+const NUMERIC_TAILS: usize = 4;
+
+/// The number of hashed tails (`~1` through `~9`) tried before falling back to
+/// the remaining numeric tails.
+const HASHED_TAILS: usize = 9;
+
+/// The largest numeric tail, `~999999`.
+const MAX_TAIL: usize = 999_999;
+
+/// Returns an 8.3 short name for the long file name `name` that is not in
+/// `taken`.
+///
+/// If `name` converts to a valid 8.3 name without losing anything other than
+/// case, the uppercased name is used as is. Otherwise, the name is uppercased
+/// and stripped of characters that are invalid in 8.3 names, and the first of
+/// the following that is not taken is used:
+///
+///   * the base name with a numeric tail `~1` through `~4`,
+///   * the first two characters of the base name followed by four hex digits
+///     hashed from `name` with a tail `~1` through `~9`,
+///   * the base name with a numeric tail `~5` through `~999999`.
+///
+/// # Errors
+///
+/// Returns an error of `Other` if every candidate is taken.
+pub(crate) fn generate(name: &str, taken: &HashSet<[u8; 11]>) -> io::Result<[u8; 11]> {
+    let (base, extension, lossy) = basis(name);
+    if !lossy {
+        let short_name = with_tail(&base, &extension, None);
+        if !taken.contains(&short_name) {
+            return Ok(short_name);
+        }
+    }
+
+    let mut hashed_base = base[..min(base.len(), 2)].to_vec();
+    hashed_base.extend_from_slice(format!("{:04X}", hash(name)).as_bytes());
+
+    let numeric = (1..NUMERIC_TAILS + 1).map(|tail| with_tail(&base, &extension, Some(tail)));
+    let hashed = (1..HASHED_TAILS + 1).map(|tail| with_tail(&hashed_base, &extension, Some(tail)));
+    let rest = (NUMERIC_TAILS + 1..MAX_TAIL + 1).map(|tail| with_tail(&base, &extension, Some(tail)));
+
+    numeric
+        .chain(hashed)
+        .chain(rest)
+        .find(|short_name| !taken.contains(short_name))
+        .ok_or(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{}: no short name is available", name),
+        ))
+}
+
+/// Returns the checksum of the 8.3 name `short_name` stored in each of the
+/// LFN entries belonging to it.
+pub(crate) fn checksum(short_name: &[u8; 11]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &c| {
+        ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(c)
+    })
+}
+
+/// Returns the uppercased base name and extension of `name`, stripped of
+/// characters that are invalid in 8.3 names, and whether anything other than
+/// case was lost in the conversion.
+fn basis(name: &str) -> (Vec<u8>, Vec<u8>, bool) {
+    fn convert(part: &str, max: usize) -> (Vec<u8>, bool) {
+        let mut converted = vec![];
+        let mut lossy = false;
+        for c in part.chars() {
+            if c == ' ' || c == '.' {
+                lossy = true;
+                continue;
+            }
+
+            let c = c.to_ascii_uppercase();
+            if c.is_ascii_alphanumeric() || "!#$%&'()-@^_`{}~".contains(c) {
+                converted.push(c as u8);
+            } else {
+                converted.push(b'_');
+                lossy = true;
+            }
+        }
+
+        if converted.len() > max {
+            converted.truncate(max);
+            lossy = true;
+        }
+
+        (converted, lossy)
+    }
+
+    let trimmed = name.trim_left_matches('.');
+    let (base, extension) = match trimmed.rfind('.') {
+        Some(i) => (&trimmed[..i], &trimmed[i + 1..]),
+        None => (trimmed, ""),
+    };
+
+    let (mut base, base_lossy) = convert(base, 8);
+    let (extension, extension_lossy) = convert(extension, 3);
+    let mut lossy = base_lossy || extension_lossy || trimmed.len() != name.len();
+    if base.is_empty() {
+        base.push(b'_');
+        lossy = true;
+    }
+
+    (base, extension, lossy)
+}
+
+/// Returns a hash of the long file name `name` used in hashed tails.
+fn hash(name: &str) -> u16 {
+    name.encode_utf16()
+        .fold(0u16, |hash, unit| hash.wrapping_mul(31).wrapping_add(unit))
+}
+
+/// Returns the 8.3 name made of `base` with the numeric tail `~tail`, if any,
+/// and `extension`, padded with spaces.
+fn with_tail(base: &[u8], extension: &[u8], tail: Option<usize>) -> [u8; 11] {
+    let tail = tail.map_or(String::new(), |tail| format!("~{}", tail));
+    let keep = min(base.len(), 8 - tail.len());
+
+    let mut short_name = [b' '; 11];
+    short_name[..keep].copy_from_slice(&base[..keep]);
+    short_name[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+    short_name[8..8 + extension.len()].copy_from_slice(extension);
+    short_name
+}
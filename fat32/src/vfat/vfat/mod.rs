@@ -1,25 +1,50 @@
 #[cfg(test)]
 mod tests;
 
+use std::cmp::min;
 use std::fmt;
 use std::io;
-use std::mem::size_of;
+use std::mem::{size_of, transmute};
+use std::ops::Range;
 use std::path::{Component, Path};
+use std::sync::{Mutex, MutexGuard};
 
 use mbr::{MasterBootRecord, PartitionEntry, PartitionType};
-use traits::{BlockDevice, FileSystem};
-use util::SliceExt;
-use vfat::{Attributes, Cluster, Dir, Entry, Error, FatEntry, File, Metadata, Shared, Status};
-use vfat::{BiosParameterBlock, CachedDevice, Partition};
+use traits::{BlockDevice, Entry as EntryTrait, FileSystem};
+use util::{SliceExt, VecExt};
+use vfat::clock;
+use vfat::dir::{self, VFatDirEntry, VFatRegularDirEntry};
+use vfat::{Attributes, Cluster, Dir, Entry, EntryLocation, Error, FatEntry, File, Metadata, Shared, Status};
+use vfat::{BiosParameterBlock, CacheStats, CachedDevice, Clock, FsInfo, Partition, Region, Timestamp};
+use vfat::OpenFiles;
 
 pub struct VFat {
-    device: CachedDevice,
+    /// The sector cache. It has a lock of its own so that reads, which update
+    /// the cache, can be made through a shared borrow of the file system.
+    /// The cache owns the device, so the lock is held for the whole of every
+    /// device access, and reads through shared borrows serialize on it.
+    device: Mutex<CachedDevice>,
     bytes_per_sector: u64,
     sectors_per_cluster: u64,
     sectors_per_fat: u64,
+    fats: u64,
+    active_fat: Option<u64>,
+    ebpb_sector: u64,
     fat_start_sector: u64,
     data_start_sector: u64,
     root_dir_cluster: Cluster,
+    data_clusters: u32,
+    free_clusters: Option<u32>,
+    next_free: u32,
+    fs_info_sector: u64,
+    fs_info: Option<FsInfo>,
+    /// Whether clusters were allocated or freed since the FSInfo sector was
+    /// last written. Nothing else changes it, so it is only rewritten then.
+    fs_info_dirty: bool,
+    clock: Box<Clock>,
+    /// The files with open handles. Every handle keeps the table, so that it
+    /// can close itself without borrowing the file system.
+    open_files: Shared<OpenFiles>,
 }
 
 impl fmt::Debug for VFat {
@@ -27,9 +52,15 @@ impl fmt::Debug for VFat {
         f.debug_struct("VFat")
             .field("bytes_per_sector", &self.bytes_per_sector)
             .field("sectors_per_fat", &self.sectors_per_fat)
+            .field("fats", &self.fats)
+            .field("active_fat", &self.active_fat)
             .field("fat_start_sector", &self.fat_start_sector)
             .field("data_start_sector", &self.data_start_sector)
             .field("root_dir_cluster", &self.root_dir_cluster)
+            .field("data_clusters", &self.data_clusters)
+            .field("free_clusters", &self.free_clusters)
+            .field("next_free", &self.next_free)
+            .field("fs_info", &self.fs_info)
             .finish()
     }
 }
@@ -52,7 +83,22 @@ impl<'a> VFat {
             ).ok_or(Error::NoBootableFatPartition)?;
         let ebpb = BiosParameterBlock::from(&mut device, partition.relative_sector as u64)?;
 
-        let vfat = VFat::from_inner(device, partition, &ebpb);
+        let mut vfat = VFat::from_inner(device, partition, &ebpb);
+        if ebpb.fs_info_sector != 0 && ebpb.fs_info_sector != 0xFFFF {
+            let sector = vfat.fs_info_sector;
+            vfat.fs_info = match FsInfo::from(vfat.device_mut(), sector) {
+                Ok(fs_info) => Some(fs_info),
+                Err(Error::BadSignature) => None,
+                Err(e) => return Err(e),
+            };
+        }
+
+        if let Some(ref fs_info) = vfat.fs_info {
+            let data_clusters = vfat.data_clusters;
+            vfat.free_clusters = fs_info.free_count().filter(|&n| n <= data_clusters);
+            vfat.next_free = fs_info.next_free().unwrap_or(2);
+        }
+
         Ok(Shared::new(vfat))
     }
 
@@ -64,35 +110,329 @@ impl<'a> VFat {
             start: partition.relative_sector as u64,
             sector_size: ebpb.bytes_per_sector as u64,
         };
-        let vfat = VFat {
-            device: CachedDevice::new(device, cache_partition.clone()),
+        let total_sectors = match ebpb.logical_sectors_small {
+            0 => ebpb.logical_sectors_large as u64,
+            n => n as u64,
+        };
+        let data_sectors = total_sectors.saturating_sub(ebpb.relative_data_start_sector());
+        let fat_entries = ebpb.sectors_per_fat as u64 * ebpb.bytes_per_sector as u64
+            / size_of::<FatEntry>() as u64;
+        let data_clusters = match ebpb.sectors_per_cluster {
+            0 => 0,
+            n => min(data_sectors / n as u64, fat_entries.saturating_sub(2)),
+        };
+
+        // With mirroring disabled, only the active FAT is read and written. An
+        // out of range active FAT falls back to the first.
+        let active_fat = if ebpb.mirroring_disabled() {
+            Some(match ebpb.active_fat() {
+                n if n < ebpb.fats => n as u64,
+                _ => 0,
+            })
+        } else {
+            None
+        };
+
+        let mut vfat = VFat {
+            device: Mutex::new(CachedDevice::new(device, cache_partition.clone())),
             bytes_per_sector: ebpb.bytes_per_sector as u64,
             sectors_per_cluster: ebpb.sectors_per_cluster as u64,
             sectors_per_fat: ebpb.sectors_per_fat as u64,
+            fats: ebpb.fats as u64,
+            active_fat,
+            ebpb_sector: partition.relative_sector as u64,
             fat_start_sector: partition.relative_sector as u64 + ebpb.relative_fat_start_sector(),
             data_start_sector: partition.relative_sector as u64 + ebpb.relative_data_start_sector(),
             root_dir_cluster: Cluster::from(ebpb.root_cluster),
+            data_clusters: data_clusters as u32,
+            free_clusters: None,
+            next_free: 2,
+            fs_info_sector: partition.relative_sector as u64 + ebpb.fs_info_sector as u64,
+            fs_info: None,
+            fs_info_dirty: false,
+            clock: clock::default_clock(),
+            open_files: Shared::new(OpenFiles::default()),
         };
 
         assert!(vfat.bytes_per_sector % (size_of::<FatEntry>() as u64) == 0);
 
+        let (fat_start, data_start) = (vfat.fat_start_sector, vfat.data_start_sector);
+        vfat.device_mut().set_layout(fat_start, data_start);
+
         vfat
     }
 
+    /// Locks and returns the sector cache.
+    fn device(&self) -> MutexGuard<CachedDevice> {
+        self.device.lock().expect("all okay")
+    }
+
+    /// Returns the sector cache, which needs no locking when `self` is
+    /// borrowed mutably.
+    fn device_mut(&mut self) -> &mut CachedDevice {
+        self.device.get_mut().expect("all okay")
+    }
+
+    /// Returns the table of open files.
+    pub(crate) fn open_files(&self) -> Shared<OpenFiles> {
+        self.open_files.clone()
+    }
+
+    /// Returns `true` if the file whose directory entry is at `location` has
+    /// an open handle.
+    pub(crate) fn is_open(&self, location: EntryLocation) -> bool {
+        self.open_files.borrow().is_open(location)
+    }
+
+    /// Sets the metadata of the open file at `location`, if any, to
+    /// `metadata`.
+    pub(crate) fn set_open_metadata(&mut self, location: EntryLocation, metadata: Metadata) {
+        self.open_files.borrow_mut().set_metadata(location, metadata)
+    }
+
+    /// Returns the generation of the table of open files. See
+    /// `OpenFiles::generation()`.
+    pub(crate) fn generation(&self) -> u64 {
+        self.open_files.borrow().generation()
+    }
+
+    /// Records that the directory entry at `location` is being deleted or
+    /// overwritten, so that handles made from it can no longer be used.
+    pub(crate) fn forget_file(&mut self, location: EntryLocation) {
+        self.open_files.borrow_mut().forget(location)
+    }
+
+    /// Writes the accessed dates recorded by reads since the last sync to the
+    /// files' directory entries.
+    pub(crate) fn write_accessed(&mut self) -> io::Result<()> {
+        let accessed = self.open_files.borrow_mut().take_accessed();
+        for (location, accessed) in accessed {
+            location.update(self, |regular| regular.set_accessed(accessed))?;
+        }
+
+        Ok(())
+    }
+
+    /// Records that the directory entry of the open file at `from`, if any,
+    /// has moved to `to`.
+    pub(crate) fn relocate_file(&mut self, from: EntryLocation, to: EntryLocation) {
+        self.open_files.borrow_mut().relocate(from, to)
+    }
+
     pub fn bytes_per_sector(&self) -> u64 {
         self.bytes_per_sector
     }
 
+    pub(crate) fn root_dir_cluster(&self) -> Cluster {
+        self.root_dir_cluster
+    }
+
+    /// Returns the number of free clusters. If the count is not known from the
+    /// FSInfo sector or an earlier call, the free clusters in the FAT are
+    /// counted.
+    pub fn free_cluster_count(&mut self) -> io::Result<u32> {
+        if let Some(free_clusters) = self.free_clusters {
+            return Ok(free_clusters);
+        }
+
+        let mut free_clusters = 0;
+        for n in 2..self.data_clusters + 2 {
+            if self.fat_entry(Cluster::from(n))?.status() == Status::Free {
+                free_clusters += 1;
+            }
+        }
+
+        self.free_clusters = Some(free_clusters);
+        Ok(free_clusters)
+    }
+
+    /// Returns the volume label, or `None` if the volume has no label. A label
+    /// in the root directory takes precedence over the one in the EBPB.
+    pub fn volume_label(&self) -> io::Result<Option<String>> {
+        let entries = self.root_dir_entries()?;
+        let label = match dir::find_volume_label(&entries) {
+            Some((_, regular)) => regular.short_name(),
+            None => {
+                let ebpb = self.read_ebpb()?;
+                let label = ebpb.volume_label;
+                if ebpb.signature != 0x29 || label == *NO_NAME {
+                    return Ok(None);
+                }
+                label
+            }
+        };
+
+        let label = String::from_utf8_lossy(&label);
+        let label = label.trim_right_matches(' ');
+        Ok(if label.is_empty() {
+            None
+        } else {
+            Some(label.to_string())
+        })
+    }
+
+    /// Sets the volume label to `label`, or removes it if `label` is empty.
+    /// The label is written both to the root directory and to the EBPB and
+    /// its backup. Lowercase letters are converted to uppercase.
+    ///
+    /// # Errors
+    ///
+    /// If `label` is longer than 11 characters, starts with a space, or
+    /// contains characters other than ASCII letters, digits, spaces and
+    /// ``!#$%&'()-@^_`{}~``, an error of `InvalidInput` is returned.
+    pub fn set_volume_label(&mut self, label: &str) -> io::Result<()> {
+        let label = volume_label_bytes(label)?;
+        let now = self.now();
+        let root = self.root_dir_cluster;
+        let entries = self.root_dir_entries()?;
+        let existing = dir::find_volume_label(&entries).map(|(slot, _)| EntryLocation {
+            dir: root,
+            first: slot,
+            slot,
+        });
+
+        match (existing, label) {
+            (Some(location), Some(label)) => {
+                location.update(self, |regular| {
+                    regular.set_short_name(&label);
+                    regular.set_modified(now);
+                })?;
+            }
+            (Some(location), None) => location.delete(self)?,
+            (None, Some(label)) => {
+                let slot = dir::reserve_slots(self, root, &entries, 1)?;
+                let attributes = Attributes::from_raw(0x08);
+                let mut regular = VFatRegularDirEntry::new(attributes, Cluster::from(0), 0, now);
+                regular.set_short_name(&label);
+                self.write_dir_entry(root, slot, &VFatDirEntry::from(regular))?;
+            }
+            (None, None) => {}
+        }
+
+        let mut ebpb = self.read_ebpb()?;
+        if ebpb.signature != 0x29 {
+            return Ok(());
+        }
+
+        ebpb.volume_label = label.unwrap_or(*NO_NAME);
+        let backup = ebpb.backup_boot_sector as u64;
+        let raw = unsafe { transmute::<BiosParameterBlock, [u8; 512]>(ebpb) };
+        let sector = self.ebpb_sector;
+        self.write_bytes(sector, 0, &raw)?;
+        if backup != 0 && backup != 0xFFFF {
+            self.write_bytes(sector + backup, 0, &raw)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads the EBPB from the first sector of the partition.
+    fn read_ebpb(&self) -> io::Result<BiosParameterBlock> {
+        let mut raw = [0u8; 512];
+        let sector = self.ebpb_sector;
+        self.read_bytes(sector, 0, &mut raw)?;
+        Ok(unsafe { transmute::<[u8; 512], BiosParameterBlock>(raw) })
+    }
+
+    /// Reads every entry of the root directory.
+    fn root_dir_entries(&self) -> io::Result<Vec<VFatDirEntry>> {
+        let root = self.root_dir_cluster;
+        self.read_dir(root)
+    }
+
+    /// Sets the clock used to timestamp directory entries as they are created,
+    /// modified and accessed. By default, the system clock is used.
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        self.clock = Box::new(clock);
+    }
+
+    /// The maximum number of device sectors held in the sector cache.
+    pub fn cache_capacity(&self) -> usize {
+        self.device().capacity()
+    }
+
+    /// Sets the maximum number of device sectors held in the sector cache to
+    /// `sectors`. Once the cache is full, the least recently used sector is
+    /// evicted, after being written back if it is dirty. The default is
+    /// 4096 sectors.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidInput` if `sectors` is zero, or an error if
+    /// a sector evicted to shrink the cache cannot be written back.
+    pub fn set_cache_capacity(&mut self, sectors: usize) -> io::Result<()> {
+        self.device_mut().set_capacity(sectors)
+    }
+
+    /// The number of device sectors read ahead of sequential accesses.
+    pub fn read_ahead(&self) -> usize {
+        self.device().read_ahead()
+    }
+
+    /// Sets the number of device sectors read ahead of sequential accesses,
+    /// such as reads of a file whose clusters are contiguous, to `sectors`.
+    /// Setting it to zero turns read-ahead off. The default is 8 sectors.
+    pub fn set_read_ahead(&mut self, sectors: usize) {
+        self.device_mut().set_read_ahead(sectors)
+    }
+
+    /// Returns statistics about the use of the sector cache.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.device().stats()
+    }
+
+    /// Resets the event counters of `cache_stats()` to zero.
+    pub fn reset_cache_stats(&mut self) {
+        self.device_mut().reset_stats();
+    }
+
+    /// Returns the current time according to the file system's clock.
+    pub(crate) fn now(&self) -> Timestamp {
+        self.clock.now()
+    }
+
+    /// The cluster at which the search for the next free cluster begins.
+    pub fn next_free_hint(&self) -> u32 {
+        self.next_free
+    }
+
+    /// Writes the accessed dates recorded by reads to the files' directory
+    /// entries, the free cluster count and the next free cluster hint to the
+    /// FSInfo sector, if the file system has one and clusters were allocated
+    /// or freed, and then every dirty cached sector back to the device.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.write_accessed()?;
+
+        let (free_count, next_free) = (self.free_clusters, Some(self.next_free));
+        let fs_info = match self.fs_info {
+            Some(ref mut fs_info) if self.fs_info_dirty => {
+                fs_info.set_free_count(free_count);
+                fs_info.set_next_free(next_free);
+                Some(*fs_info.as_bytes())
+            }
+            _ => None,
+        };
+
+        if let Some(fs_info) = fs_info {
+            let sector = self.fs_info_sector;
+            self.write_bytes(sector, 0, &fs_info)?;
+            self.fs_info_dirty = false;
+        }
+
+        self.device_mut().flush()
+    }
+
     //  * A method to read all of the clusters chained from a starting cluster
     //    into a vector.
     //
     pub fn read_chain(
-        &mut self,
+        &self,
         start: Cluster,
-        mut buf: &mut Vec<u8>,
+        buf: &mut Vec<u8>,
         max: Option<usize>,
     ) -> io::Result<usize> {
         let sectors_per_cluster = self.sectors_per_cluster;
+        let cluster_size = self.cluster_size_bytes();
 
         let entries =
             FatIter::new(self, start).collect::<io::Result<Vec<(Cluster, FatEntry)>>>()?;
@@ -103,11 +443,11 @@ impl<'a> VFat {
             match status {
                 Status::Data(_) | Status::Eoc(_) => {
                     let cluster_sector = self.cluster_sector(&cluster);
-                    for i in 0..sectors_per_cluster {
-                        n += self
-                            .device
-                            .read_all_sector(cluster_sector + i as u64, &mut buf)?;
-                    }
+                    let len = buf.len();
+                    buf.resize(len + cluster_size, 0);
+                    n += self
+                        .device()
+                        .read_sectors(cluster_sector, sectors_per_cluster, &mut buf[len..])?;
                 }
                 status => {
                     return Err(io::Error::new(
@@ -130,18 +470,346 @@ impl<'a> VFat {
     //  * A method to return a reference to a `FatEntry` for a cluster where the
     //    reference points directly into a cached sector.
     //
-    fn fat_entry(&mut self, cluster: Cluster) -> io::Result<FatEntry> {
+    fn fat_entry(&self, cluster: Cluster) -> io::Result<FatEntry> {
         let n = cluster.get();
-        let sector = self.fat_entry_sector(n);
+        let sector = self.fat_entry_sector(n) + self.active_fat.unwrap_or(0) * self.sectors_per_fat;
         let offset = self.fat_sector_offset(n);
-        let (offset, sector) = self
-            .device
-            .get_logical(sector, offset * size_of::<FatEntry>())?;
+        let mut device = self.device();
+        let (offset, sector) = device.get_logical(sector, offset * size_of::<FatEntry>())?;
         let offset = offset / size_of::<FatEntry>();
         let fat_entries = unsafe { sector.cast::<FatEntry>() };
         Ok(fat_entries[offset])
     }
 
+    /// Returns the cluster following `cluster` in its chain, or `None` if
+    /// `cluster` is the last.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidData` if `cluster` is free, bad or
+    /// reserved, as `chain()` does.
+    pub(crate) fn next_cluster(&self, cluster: Cluster) -> io::Result<Option<Cluster>> {
+        match self.fat_entry(cluster)?.status() {
+            Status::Data(next) => Ok(Some(next)),
+            Status::Eoc(_) => Ok(None),
+            status => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid cluster chain: {:?}", status),
+            )),
+        }
+    }
+
+    /// Overwrites the FAT entry for `cluster` with `entry`, preserving the
+    /// entry's reserved upper four bits. The entry is written to every copy of
+    /// the FAT unless mirroring is disabled, in which case only the active FAT
+    /// is written.
+    fn set_fat_entry(&mut self, cluster: Cluster, entry: FatEntry) -> io::Result<()> {
+        let n = cluster.get();
+        let offset = self.fat_sector_offset(n) * size_of::<FatEntry>();
+        for fat in self.written_fats() {
+            let sector = self.fat_entry_sector(n) + fat * self.sectors_per_fat;
+            let (offset, sector) = self.device_mut().get_logical_mut(sector, offset)?;
+            let offset = offset / size_of::<FatEntry>();
+            let fat_entries = unsafe { sector.cast_mut::<FatEntry>() };
+            let reserved = fat_entries[offset].0 & (0xF << 28);
+            fat_entries[offset] = FatEntry(reserved | (entry.0 & !(0xF << 28)));
+        }
+
+        Ok(())
+    }
+
+    /// Allocates a free cluster and marks it as the end of its chain. If
+    /// `prev` is `Some`, the new cluster is linked after `prev`.
+    ///
+    /// The contents of the new cluster are left as they were on disk.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `Other` if there are no free clusters left.
+    pub(crate) fn alloc_cluster(&mut self, prev: Option<Cluster>) -> io::Result<Cluster> {
+        let end = self.data_clusters + 2;
+        let hint = if self.next_free >= 2 && self.next_free < end {
+            self.next_free
+        } else {
+            2
+        };
+
+        for n in (hint..end).chain(2..hint) {
+            let cluster = Cluster::from(n);
+            if self.fat_entry(cluster)?.status() != Status::Free {
+                continue;
+            }
+
+            self.set_fat_entry(cluster, FatEntry::EOC)?;
+            if let Some(prev) = prev {
+                self.set_fat_entry(prev, FatEntry(cluster.get()))?;
+            }
+
+            self.next_free = n + 1;
+            self.free_clusters = self.free_clusters.map(|free| free.saturating_sub(1));
+            self.fs_info_dirty = true;
+            return Ok(cluster);
+        }
+
+        Err(io::Error::new(io::ErrorKind::Other, "no free clusters"))
+    }
+
+    /// Marks every cluster in the chain starting at `start` as free.
+    pub(crate) fn free_chain(&mut self, start: Cluster) -> io::Result<()> {
+        let chain = self.chain(start)?;
+        for &cluster in chain.iter() {
+            self.set_fat_entry(cluster, FatEntry::FREE)?;
+        }
+
+        self.free_clusters = self.free_clusters.map(|free| free + chain.len() as u32);
+        self.fs_info_dirty = true;
+        Ok(())
+    }
+
+    /// Marks `last` as the end of its chain and frees every cluster that
+    /// followed it.
+    pub(crate) fn truncate_chain(&mut self, last: Cluster) -> io::Result<()> {
+        let chain = self.chain(last)?;
+        if let Some(&next) = chain.get(1) {
+            self.set_fat_entry(last, FatEntry::EOC)?;
+            self.free_chain(next)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns every cluster in the chain starting at `start`, in order.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidData` if the chain contains a free, bad or
+    /// reserved cluster or is longer than the number of clusters on the disk.
+    pub(crate) fn chain(&self, start: Cluster) -> io::Result<Vec<Cluster>> {
+        let max = self.data_clusters as usize;
+        let mut clusters = vec![];
+        for result in FatIter::new(self, start) {
+            let (cluster, entry) = result?;
+            match entry.status() {
+                Status::Data(_) | Status::Eoc(_) => clusters.push(cluster),
+                status => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid cluster chain: {:?}", status),
+                    ))
+                }
+            }
+
+            if clusters.len() > max {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "cluster chain contains a cycle",
+                ));
+            }
+        }
+
+        Ok(clusters)
+    }
+
+    /// Reads `buf.len()` bytes starting at byte `offset` of `cluster`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidInput` if the read extends past the end of
+    /// the cluster.
+    pub(crate) fn read_cluster(
+        &self,
+        cluster: Cluster,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> io::Result<()> {
+        self.check_cluster_bounds(offset, buf.len())?;
+        let sector = self.cluster_sector(&cluster);
+        self.read_bytes(sector, offset, buf)?;
+        self.set_cluster_region(cluster, Region::Data);
+        Ok(())
+    }
+
+    /// Writes `buf` into `cluster` starting at byte `offset`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidInput` if the write extends past the end of
+    /// the cluster.
+    pub(crate) fn write_cluster(
+        &mut self,
+        cluster: Cluster,
+        offset: usize,
+        buf: &[u8],
+    ) -> io::Result<()> {
+        self.check_cluster_bounds(offset, buf.len())?;
+        let sector = self.cluster_sector(&cluster);
+        self.write_bytes(sector, offset, buf)?;
+        self.set_cluster_region(cluster, Region::Data);
+        Ok(())
+    }
+
+    /// Reads the `count` consecutive clusters starting at `cluster` straight
+    /// from the device into `buf` with a single read, bypassing the sector
+    /// cache. Unwritten changes held in the cache are still reflected in
+    /// `buf`. The cache stays locked for the whole read, so other readers'
+    /// device accesses wait for it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `InvalidInput` if `buf` is shorter than the
+    /// clusters being read.
+    pub(crate) fn read_clusters_direct(
+        &self,
+        cluster: Cluster,
+        count: usize,
+        buf: &mut [u8],
+    ) -> io::Result<()> {
+        let sector = self.cluster_sector(&cluster);
+        let sectors = count as u64 * self.sectors_per_cluster;
+        self.device().read_direct(sector, sectors, buf)?;
+        Ok(())
+    }
+
+    /// Reads all of `cluster`, which holds directory entries, into `buf`.
+    pub(crate) fn read_dir_cluster(&self, cluster: Cluster, buf: &mut [u8]) -> io::Result<()> {
+        self.check_cluster_bounds(0, buf.len())?;
+        let sector = self.cluster_sector(&cluster);
+        self.read_bytes(sector, 0, buf)?;
+        self.set_cluster_region(cluster, Region::Directory);
+        Ok(())
+    }
+
+    /// Reads every entry of the directory whose chain begins at `dir`.
+    pub(crate) fn read_dir(&self, dir: Cluster) -> io::Result<Vec<VFatDirEntry>> {
+        let cluster_size = self.cluster_size_bytes();
+        let chain = self.chain(dir)?;
+        let mut buf = vec![0; chain.len() * cluster_size];
+        for (&cluster, data) in chain.iter().zip(buf.chunks_mut(cluster_size)) {
+            self.read_dir_cluster(cluster, data)?;
+        }
+
+        Ok(unsafe { buf.cast::<VFatDirEntry>() })
+    }
+
+    /// Attributes the cached sectors of `cluster` to `region` in the cache
+    /// statistics.
+    fn set_cluster_region(&self, cluster: Cluster, region: Region) {
+        let sector = self.cluster_sector(&cluster);
+        let mut device = self.device();
+        for i in 0..self.sectors_per_cluster {
+            device.set_region(sector + i, region);
+        }
+    }
+
+    /// Reads `buf.len()` bytes starting at byte `offset` of the logical sector
+    /// `sector`. The read may continue into the sectors following `sector`.
+    /// Whole sectors are read with a single batched read.
+    fn read_bytes(&self, sector: u64, offset: usize, buf: &mut [u8]) -> io::Result<()> {
+        let bytes_per_sector = self.bytes_per_sector as usize;
+        let mut device = self.device();
+        let mut read = 0;
+        while read < buf.len() {
+            let position = offset + read;
+            let whole_sectors = (buf.len() - read) / bytes_per_sector;
+            if position % bytes_per_sector == 0 && whole_sectors > 0 {
+                let len = whole_sectors * bytes_per_sector;
+                device.read_sectors(
+                    sector + (position / bytes_per_sector) as u64,
+                    whole_sectors as u64,
+                    &mut buf[read..read + len],
+                )?;
+                read += len;
+                continue;
+            }
+
+            let (start, data) = device.get_logical(
+                sector + (position / bytes_per_sector) as u64,
+                position % bytes_per_sector,
+            )?;
+            let n = min(data.len() - start, buf.len() - read);
+            buf[read..read + n].copy_from_slice(&data[start..start + n]);
+            read += n;
+        }
+
+        Ok(())
+    }
+
+    /// Writes `buf` starting at byte `offset` of the logical sector `sector`.
+    /// The write may continue into the sectors following `sector`.
+    fn write_bytes(&mut self, sector: u64, offset: usize, buf: &[u8]) -> io::Result<()> {
+        let bytes_per_sector = self.bytes_per_sector as usize;
+        let device = self.device_mut();
+        let mut written = 0;
+        while written < buf.len() {
+            let position = offset + written;
+            let (start, data) = device.get_logical_mut(
+                sector + (position / bytes_per_sector) as u64,
+                position % bytes_per_sector,
+            )?;
+            let n = min(data.len() - start, buf.len() - written);
+            data[start..start + n].copy_from_slice(&buf[written..written + n]);
+            written += n;
+        }
+
+        Ok(())
+    }
+
+    /// Overwrites the entire contents of `cluster` with zeroes.
+    pub(crate) fn zero_cluster(&mut self, cluster: Cluster) -> io::Result<()> {
+        let zeroes = vec![0; self.cluster_size_bytes()];
+        self.write_cluster(cluster, 0, &zeroes)
+    }
+
+    fn check_cluster_bounds(&self, offset: usize, len: usize) -> io::Result<()> {
+        if offset + len > self.cluster_size_bytes() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "access extends past the end of the cluster",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the cluster holding entry number `slot` of the directory whose
+    /// chain begins at `dir`, along with the entry's byte offset in it.
+    fn dir_slot(&self, dir: Cluster, slot: usize) -> io::Result<(Cluster, usize)> {
+        let offset = slot * size_of::<VFatDirEntry>();
+        let cluster_size = self.cluster_size_bytes();
+        let (cluster, _) = FatIter::new(self, dir)
+            .nth(offset / cluster_size)
+            .ok_or(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "entry is past the end of the directory",
+            ))??;
+
+        Ok((cluster, offset % cluster_size))
+    }
+
+    /// Reads entry number `slot` of the directory whose chain begins at `dir`.
+    pub(crate) fn read_dir_entry(&self, dir: Cluster, slot: usize) -> io::Result<VFatDirEntry> {
+        let (cluster, offset) = self.dir_slot(dir, slot)?;
+        let mut buf = [0u8; 32];
+        self.read_cluster(cluster, offset, &mut buf)?;
+        self.set_cluster_region(cluster, Region::Directory);
+        Ok(unsafe { transmute::<[u8; 32], VFatDirEntry>(buf) })
+    }
+
+    /// Overwrites entry number `slot` of the directory whose chain begins at
+    /// `dir` with `entry`.
+    pub(crate) fn write_dir_entry(
+        &mut self,
+        dir: Cluster,
+        slot: usize,
+        entry: &VFatDirEntry,
+    ) -> io::Result<()> {
+        let (cluster, offset) = self.dir_slot(dir, slot)?;
+        let buf = unsafe { transmute::<VFatDirEntry, [u8; 32]>(*entry) };
+        self.write_cluster(cluster, offset, &buf)?;
+        self.set_cluster_region(cluster, Region::Directory);
+        Ok(())
+    }
+
     pub fn cluster_size_bytes(&self) -> usize {
         (self.bytes_per_sector * self.sectors_per_cluster) as usize
     }
@@ -150,6 +818,13 @@ impl<'a> VFat {
         self.data_start_sector + self.sectors_per_cluster * (cluster.get() as u64 - 2)
     }
 
+    fn written_fats(&self) -> Range<u64> {
+        match self.active_fat {
+            Some(fat) => fat..fat + 1,
+            None => 0..self.fats,
+        }
+    }
+
     fn fat_entry_sector(&self, n: u32) -> u64 {
         self.fat_start_sector + (n as u64 / self.fats_per_sector())
     }
@@ -163,13 +838,21 @@ impl<'a> VFat {
     }
 }
 
+impl Drop for VFat {
+    /// Syncs the file system when the last `Shared<VFat>` is dropped. Errors
+    /// are ignored; call `sync()` beforehand to observe them.
+    fn drop(&mut self) {
+        let _ = self.sync();
+    }
+}
+
 struct FatIter<'a> {
-    vfat: &'a mut VFat,
+    vfat: &'a VFat,
     current: Option<Cluster>,
 }
 
 impl<'a> FatIter<'a> {
-    fn new(vfat: &'a mut VFat, cluster: Cluster) -> FatIter {
+    fn new(vfat: &'a VFat, cluster: Cluster) -> FatIter {
         FatIter {
             vfat,
             current: Some(cluster),
@@ -196,6 +879,149 @@ impl<'a> Iterator for FatIter<'a> {
     }
 }
 
+/// The volume label of a volume without a label.
+const NO_NAME: &[u8; 11] = b"NO NAME    ";
+
+/// Returns `label` as a space-padded, uppercased volume label, or `None` if
+/// `label` is empty.
+///
+/// # Errors
+///
+/// If `label` is not a valid volume label, an error kind of `InvalidInput` is
+/// returned.
+fn volume_label_bytes(label: &str) -> io::Result<Option<[u8; 11]>> {
+    if label.is_empty() {
+        return Ok(None);
+    }
+
+    let valid = label.len() <= 11
+        && !label.starts_with(' ')
+        && label
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || " !#$%&'()-@^_`{}~".contains(c));
+    if !valid {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}: invalid volume label", label),
+        ));
+    }
+
+    let mut raw = [b' '; 11];
+    raw[..label.len()].copy_from_slice(label.to_ascii_uppercase().as_bytes());
+    Ok(Some(raw))
+}
+
+/// Splits the absolute path `path` into its parent directory, which is opened,
+/// and the name of its last component.
+///
+/// # Errors
+///
+/// If `path` is not absolute, has no last component, or its parent is not an
+/// existing directory, an error kind of `InvalidInput` is returned.
+fn open_parent<'p>(vfat: &Shared<VFat>, path: &'p Path) -> io::Result<(Dir, &'p str)> {
+    if !path.is_absolute() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "path must be absolute",
+        ));
+    }
+
+    let (parent, name) = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => (parent, name),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "path has no final component",
+            ))
+        }
+    };
+
+    let name = name.to_str().ok_or(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "name is not valid utf-8",
+    ))?;
+
+    let parent = vfat.open_dir(parent).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::Other => io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}: not a directory", parent.display()),
+        ),
+        _ => e,
+    })?;
+
+    Ok((parent, name))
+}
+
+/// Creates each directory leading up to the last component of the absolute
+/// path `path` that does not already exist.
+///
+/// # Errors
+///
+/// If `path` is not absolute or one of the components leading up to its last
+/// refers to an entry that is not a directory, an error kind of
+/// `InvalidInput` is returned.
+fn create_ancestors(vfat: &Shared<VFat>, path: &Path) -> io::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) => parent,
+        None => return Ok(()),
+    };
+
+    let mut components = parent.components();
+    if components.next() != Some(Component::RootDir) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "path must be absolute",
+        ));
+    }
+
+    let mut dir = vfat.open_dir("/")?;
+    for component in components {
+        let name = match component {
+            Component::Normal(name) => name.to_str().ok_or(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "name is not valid utf-8",
+            ))?,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "unsupported component type",
+                ))
+            }
+        };
+
+        dir = match dir.find(name) {
+            Ok(Entry::Dir(dir)) => dir,
+            Ok(Entry::File(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{}: not a directory", name),
+                ))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => dir.create_dir(name)?,
+            Err(e) => return Err(e),
+        };
+    }
+
+    Ok(())
+}
+
+impl Shared<VFat> {
+    /// Sets the attributes of the entry at `path` to `attributes`. `path` must
+    /// be absolute.
+    ///
+    /// # Errors
+    ///
+    /// If `path` is not absolute, its parent is not an existing directory, or
+    /// `attributes` sets the volume ID bit or changes the directory bit, an
+    /// error kind of `InvalidInput` is returned.
+    ///
+    /// If there is no entry at `path`, an error kind of `NotFound` is returned.
+    pub fn set_attributes<P: AsRef<Path>>(&self, path: P, attributes: Attributes) -> io::Result<()> {
+        let (parent, name) = open_parent(self, path.as_ref())?;
+        parent.set_attributes(name, attributes)
+    }
+}
+
 impl<'a> FileSystem for &'a Shared<VFat> {
     type File = File;
     type Dir = Dir;
@@ -242,26 +1068,46 @@ impl<'a> FileSystem for &'a Shared<VFat> {
         })
     }
 
-    fn create_file<P: AsRef<Path>>(self, _path: P) -> io::Result<Self::File> {
-        unimplemented!("read only file system")
+    /// Opens the file at `path` and adds the handle to the table of open
+    /// files, so the file cannot be removed while the handle is open.
+    fn open_file<P: AsRef<Path>>(self, path: P) -> io::Result<Self::File> {
+        let mut file = self
+            .open(path)?
+            .into_file()
+            .ok_or(io::Error::new(io::ErrorKind::Other, "not a regular file"))?;
+        file.register()?;
+        Ok(file)
+    }
+
+    fn create_file<P: AsRef<Path>>(self, path: P) -> io::Result<Self::File> {
+        let (parent, name) = open_parent(self, path.as_ref())?;
+        parent.create_file(name)
     }
 
-    fn create_dir<P>(self, _path: P, _parents: bool) -> io::Result<Self::Dir>
+    fn create_dir<P>(self, path: P, parents: bool) -> io::Result<Self::Dir>
     where
         P: AsRef<Path>,
     {
-        unimplemented!("read only file system")
+        if parents {
+            create_ancestors(self, path.as_ref())?;
+        }
+
+        let (parent, name) = open_parent(self, path.as_ref())?;
+        parent.create_dir(name)
     }
 
-    fn rename<P, Q>(self, _from: P, _to: Q) -> io::Result<()>
+    fn rename<P, Q>(self, from: P, to: Q) -> io::Result<()>
     where
         P: AsRef<Path>,
         Q: AsRef<Path>,
     {
-        unimplemented!("read only file system")
+        let (from_parent, from_name) = open_parent(self, from.as_ref())?;
+        let (to_parent, to_name) = open_parent(self, to.as_ref())?;
+        from_parent.rename(from_name, &to_parent, to_name)
     }
 
-    fn remove<P: AsRef<Path>>(self, _path: P, _children: bool) -> io::Result<()> {
-        unimplemented!("read only file system")
+    fn remove<P: AsRef<Path>>(self, path: P, children: bool) -> io::Result<()> {
+        let (parent, name) = open_parent(self, path.as_ref())?;
+        parent.remove(name, children)
     }
 }
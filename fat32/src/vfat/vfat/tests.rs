@@ -1,8 +1,178 @@
 use super::VFat;
 use mbr::PartitionEntry;
-use std::io::Cursor;
-use traits::BlockDevice;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::mem::transmute;
+use std::sync::mpsc;
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use std::time::Duration;
+use traits::{BlockDevice, Dir as DirTrait, Entry as EntryTrait, File as FileTrait, FileSystem};
+use traits::{Metadata as MetadataTrait, Timestamp as TimestampTrait};
 use vfat::ebpb::BiosParameterBlock;
+use vfat::{Attributes, CachedDevice, Cluster, Entry, FatEntry, FixedClock, FsInfo, ManualClock, Partition, Shared, Status, Timestamp};
+
+/// The sector at which the partition of `mock_image()` starts.
+const PARTITION_START: usize = 1;
+const SECTOR_SIZE: usize = 512;
+const RESERVED_SECTORS: usize = 2;
+const SECTORS_PER_FAT: usize = 2;
+const DATA_CLUSTERS: usize = 250;
+
+/// Writes the little-endian `value` into `image` at byte `offset`.
+fn put_u32(image: &mut [u8], offset: usize, value: u32) {
+    let bytes = unsafe { transmute::<u32, [u8; 4]>(value) };
+    image[offset..offset + 4].copy_from_slice(&bytes);
+}
+
+/// Returns a freshly formatted image holding a single FAT32 partition with
+/// 512-byte sectors, one sector per cluster, two FATs, an FSInfo sector and
+/// an empty root directory in cluster 2.
+fn mock_image() -> Vec<u8> {
+    let partition_sectors = RESERVED_SECTORS + 2 * SECTORS_PER_FAT + DATA_CLUSTERS;
+    let mut image = vec![0u8; (PARTITION_START + partition_sectors) * SECTOR_SIZE];
+
+    let entry = 446;
+    image[entry] = 0x80;
+    image[entry + 4] = 0x0C;
+    image[entry + 8] = PARTITION_START as u8;
+    image[entry + 12] = partition_sectors as u8;
+    image[entry + 13] = (partition_sectors >> 8) as u8;
+    image[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+    let ebpb = BiosParameterBlock {
+        _asm: [0xEB, 0x58, 0x90],
+        oem_id: *b"mkfs.fat",
+        bytes_per_sector: SECTOR_SIZE as u16,
+        sectors_per_cluster: 1,
+        reserved_sectors: RESERVED_SECTORS as u16,
+        fats: 2,
+        fat_id: 0xF8,
+        logical_sectors_large: partition_sectors as u32,
+        sectors_per_fat: SECTORS_PER_FAT as u32,
+        root_cluster: 2,
+        fs_info_sector: 1,
+        signature: 0x29,
+        volume_label: *b"NO NAME    ",
+        system_id: *b"FAT32   ",
+        partition_signature: [0x55, 0xAA],
+        ..Default::default()
+    };
+    let ebpb = unsafe { transmute::<BiosParameterBlock, [u8; 512]>(ebpb) };
+    let start = PARTITION_START * SECTOR_SIZE;
+    image[start..start + SECTOR_SIZE].copy_from_slice(&ebpb);
+
+    let fs_info = (PARTITION_START + 1) * SECTOR_SIZE;
+    put_u32(&mut image, fs_info, 0x41615252);
+    put_u32(&mut image, fs_info + 484, 0x61417272);
+    put_u32(&mut image, fs_info + 488, DATA_CLUSTERS as u32 - 1);
+    put_u32(&mut image, fs_info + 492, 3);
+    put_u32(&mut image, fs_info + 508, 0xAA550000);
+
+    for fat in 0..2 {
+        let start = (PARTITION_START + RESERVED_SECTORS + fat * SECTORS_PER_FAT) * SECTOR_SIZE;
+        for (i, &entry) in [0x0FFFFFF8u32, 0x0FFFFFFF, 0x0FFFFFFF].iter().enumerate() {
+            put_u32(&mut image, start + i * 4, entry);
+        }
+    }
+
+    image
+}
+
+/// A block device over an image that stays readable after the device has
+/// been handed to a `VFat`.
+#[derive(Clone)]
+struct SharedImage(Arc<Mutex<Cursor<Vec<u8>>>>);
+
+impl SharedImage {
+    fn new(image: Vec<u8>) -> SharedImage {
+        SharedImage(Arc::new(Mutex::new(Cursor::new(image))))
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        self.0.lock().unwrap().get_ref().clone()
+    }
+}
+
+impl BlockDevice for SharedImage {
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read_sector(n, buf)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write_sector(n, buf)
+    }
+}
+
+/// A block device over an image that logs each read and write it is asked to
+/// make as `(write, start, count)`.
+#[derive(Clone)]
+struct LoggedImage {
+    image: SharedImage,
+    log: Arc<Mutex<Vec<(bool, u64, u64)>>>,
+}
+
+impl LoggedImage {
+    fn new(image: Vec<u8>) -> LoggedImage {
+        LoggedImage {
+            image: SharedImage::new(image),
+            log: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    fn take_log(&self) -> Vec<(bool, u64, u64)> {
+        ::std::mem::replace(&mut *self.log.lock().unwrap(), vec![])
+    }
+}
+
+impl BlockDevice for LoggedImage {
+    fn read_sector(&mut self, n: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.log.lock().unwrap().push((false, n, 1));
+        self.image.0.lock().unwrap().read_sector(n, buf)
+    }
+
+    fn write_sector(&mut self, n: u64, buf: &[u8]) -> io::Result<usize> {
+        self.log.lock().unwrap().push((true, n, 1));
+        self.image.0.lock().unwrap().write_sector(n, buf)
+    }
+
+    fn read_sectors(&mut self, start: u64, count: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.log.lock().unwrap().push((false, start, count));
+        self.image.0.lock().unwrap().read_sectors(start, count, buf)
+    }
+
+    fn write_sectors(&mut self, start: u64, count: u64, buf: &[u8]) -> io::Result<usize> {
+        self.log.lock().unwrap().push((true, start, count));
+        self.image.0.lock().unwrap().write_sectors(start, count, buf)
+    }
+}
+
+fn mock_vfat() -> Shared<VFat> {
+    VFat::from(Cursor::new(mock_image())).expect("valid mock image")
+}
+
+/// Returns the entry for `cluster` in each copy of the FAT.
+fn fat_entries(vfat: &Shared<VFat>, cluster: Cluster) -> Vec<u32> {
+    let mut vfat = vfat.borrow_mut();
+    let n = cluster.get();
+    let offset = vfat.fat_sector_offset(n) * 4;
+    (0..vfat.fats)
+        .map(|fat| {
+            let sector = vfat.fat_entry_sector(n) + fat * vfat.sectors_per_fat;
+            let (offset, data) = vfat.device_mut().get_logical(sector, offset).expect("FAT sector");
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&data[offset..offset + 4]);
+            unsafe { transmute::<[u8; 4], u32>(bytes) }
+        }).collect()
+}
+
+fn root_names(vfat: &Shared<VFat>) -> Vec<String> {
+    vfat.open_dir("/")
+        .expect("root directory")
+        .entries()
+        .expect("root entries")
+        .map(|entry| entry.name().to_string())
+        .collect()
+}
 
 #[test]
 fn vfat() {
@@ -32,3 +202,1349 @@ fn vfat() {
     assert_eq!(vfat.fat_start_sector, 4);
     assert_eq!(vfat.data_start_sector, 8);
 }
+
+#[test]
+fn create_file() {
+    let vfat = mock_vfat();
+    let file = vfat.create_file("/Hello World.txt").expect("created");
+    assert_eq!(file.name(), "Hello World.txt");
+    assert_eq!(file.size(), 0);
+    assert!(file.metadata().attributes.archive());
+
+    let entry = vfat.open("/hello world.TXT").expect("entry exists");
+    assert!(entry.is_file());
+    assert_eq!(entry.name(), "Hello World.txt");
+    assert_eq!(root_names(&vfat), vec!["Hello World.txt"]);
+}
+
+#[test]
+fn create_file_errors() {
+    let vfat = mock_vfat();
+    vfat.create_file("/a.txt").expect("created");
+
+    let kind = |result: io::Result<::vfat::File>| result.map(|_| ()).unwrap_err().kind();
+    assert_eq!(kind(vfat.create_file("/A.TXT")), io::ErrorKind::AlreadyExists);
+    assert_eq!(kind(vfat.create_file("a.txt")), io::ErrorKind::InvalidInput);
+    assert_eq!(kind(vfat.create_file("/missing/b.txt")), io::ErrorKind::InvalidInput);
+    assert_eq!(kind(vfat.create_file("/a.txt/b.txt")), io::ErrorKind::InvalidInput);
+    assert_eq!(kind(vfat.create_file("/what?")), io::ErrorKind::InvalidInput);
+    assert_eq!(kind(vfat.create_file("/")), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn create_file_grows_directory() {
+    let vfat = mock_vfat();
+    let names: Vec<String> = (0..40)
+        .map(|i| format!("a rather long file name number {}.data", i))
+        .collect();
+    for name in names.iter() {
+        vfat.create_file(format!("/{}", name)).expect("created");
+    }
+
+    assert_eq!(root_names(&vfat), names);
+    let root = vfat.borrow().root_dir_cluster;
+    assert!(vfat.borrow_mut().chain(root).unwrap().len() > 1);
+}
+
+fn read_file(vfat: &Shared<VFat>, path: &str) -> Vec<u8> {
+    let mut file = vfat.open_file(path).expect("file exists");
+    let mut data = vec![];
+    file.read_to_end(&mut data).expect("read file");
+    assert_eq!(data.len() as u64, file.size());
+    data
+}
+
+#[test]
+fn write_file() {
+    let vfat = mock_vfat();
+    let mut file = vfat.create_file("/log.txt").expect("created");
+    file.write_all(b"hello, ").expect("written");
+    file.write_all(b"world").expect("written");
+    assert_eq!(file.size(), 12);
+
+    file.seek(SeekFrom::Start(0)).expect("seek");
+    let mut data = vec![];
+    file.read_to_end(&mut data).expect("read");
+    assert_eq!(&data[..], b"hello, world");
+
+    assert_eq!(&read_file(&vfat, "/log.txt")[..], b"hello, world");
+}
+
+#[test]
+fn write_file_across_clusters() {
+    let vfat = mock_vfat();
+    let data: Vec<u8> = (0..1300).map(|i| (i % 251) as u8).collect();
+    let mut file = vfat.create_file("/data.bin").expect("created");
+    file.write_all(&data).expect("written");
+    assert_eq!(read_file(&vfat, "/data.bin"), data);
+
+    let mut file = vfat.open_file("/data.bin").expect("file exists");
+    file.seek(SeekFrom::Start(500)).expect("seek");
+    file.write_all(&[0xFF; 100]).expect("overwritten");
+    assert_eq!(file.size(), 1300);
+
+    let mut expected = data.clone();
+    for byte in expected[500..600].iter_mut() {
+        *byte = 0xFF;
+    }
+    assert_eq!(read_file(&vfat, "/data.bin"), expected);
+}
+
+#[test]
+fn create_dir() {
+    let vfat = mock_vfat();
+    let config = vfat.create_dir("/config", false).expect("created");
+    assert!(config.metadata().attributes.directory());
+
+    let names: Vec<String> = config
+        .entries()
+        .expect("entries")
+        .map(|entry| entry.name().to_string())
+        .collect();
+    assert_eq!(names, vec![".", ".."]);
+
+    let dot = config.find(".").expect("dot").into_dir().expect("dir");
+    let dotdot = config.find("..").expect("dotdot").into_dir().expect("dir");
+    assert_eq!(dot.start(), config.start());
+    assert_eq!(dotdot.start(), Cluster::from(0));
+
+    let net = vfat.create_dir("/config/net", false).expect("created");
+    let dotdot = net.find("..").expect("dotdot").into_dir().expect("dir");
+    assert_eq!(dotdot.start(), config.start());
+
+    let entry = vfat.open("/CONFIG/net").expect("entry exists");
+    match entry {
+        Entry::Dir(ref dir) => assert_eq!(dir.start(), net.start()),
+        _ => panic!("expected a directory"),
+    }
+}
+
+#[test]
+fn create_dir_parents() {
+    let vfat = mock_vfat();
+    let kind = |result: io::Result<::vfat::Dir>| result.map(|_| ()).unwrap_err().kind();
+    assert_eq!(kind(vfat.create_dir("/a/b/c", false)), io::ErrorKind::InvalidInput);
+
+    vfat.create_dir("/a/b/c", true).expect("created");
+    vfat.open_dir("/a/b/c").expect("directory exists");
+    vfat.create_dir("/a/b/d", true).expect("created");
+    assert_eq!(kind(vfat.create_dir("/a/b/c", true)), io::ErrorKind::AlreadyExists);
+
+    vfat.create_file("/a/file").expect("created");
+    assert_eq!(kind(vfat.create_dir("/a/file/e", true)), io::ErrorKind::InvalidInput);
+    assert_eq!(kind(vfat.create_dir("a/e", true)), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn create_dir_out_of_space() {
+    let vfat = mock_vfat();
+
+    // Eight entries of two slots each fill the root directory's only cluster.
+    for i in 0..7 {
+        vfat.create_file(format!("/f{}", i)).expect("created");
+    }
+    let mut big = vfat.create_file("/big").expect("created");
+    let free = vfat.borrow_mut().free_cluster_count().unwrap();
+    big.set_len((free as u64 - 1) * SECTOR_SIZE as u64).expect("extended");
+    assert_eq!(vfat.borrow_mut().free_cluster_count().unwrap(), 1);
+
+    // The last cluster goes to the new directory, leaving none to grow the
+    // root directory for its entry.
+    let err = vfat.create_dir("/logs", false).map(|_| ()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+    assert_eq!(vfat.borrow_mut().free_cluster_count().unwrap(), 1);
+    assert_eq!(root_names(&vfat).len(), 8);
+}
+
+#[test]
+fn remove_file() {
+    let vfat = mock_vfat();
+    let mut file = vfat.create_file("/kernel8.img").expect("created");
+    file.write_all(&[0xAB; 1500]).expect("written");
+
+    let root = vfat.borrow().root_dir_cluster;
+    let chain = vfat.borrow_mut().chain(Cluster::from(3)).expect("chain");
+    assert_eq!(chain.len(), 3);
+    assert_eq!(fat_entries(&vfat, chain[2]), vec![FatEntry::EOC.0; 2]);
+
+    drop(file);
+    vfat.remove("/kernel8.img", false).expect("removed");
+    assert_eq!(
+        vfat.open("/kernel8.img").unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+    assert!(root_names(&vfat).is_empty());
+    for &cluster in chain.iter() {
+        assert_eq!(fat_entries(&vfat, cluster), vec![0, 0]);
+    }
+    assert_eq!(vfat.borrow_mut().fat_entry(root).unwrap().status(), Status::Eoc(0x0FFFFFFF));
+
+    assert_eq!(
+        vfat.remove("/kernel8.img", false).unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn remove_dir() {
+    let vfat = mock_vfat();
+    vfat.create_dir("/empty", false).expect("created");
+    vfat.remove("/empty", false).expect("empty directory removed");
+
+    vfat.create_dir("/config/net", true).expect("created");
+    let mut file = vfat.create_file("/config/net/interfaces").expect("created");
+    file.write_all(b"auto eth0").expect("written");
+    drop(file);
+    let net = vfat.open_dir("/config/net").expect("directory exists");
+
+    assert_eq!(
+        vfat.remove("/config", false).unwrap_err().kind(),
+        io::ErrorKind::Other
+    );
+    vfat.remove("/config", true).expect("removed recursively");
+    assert!(root_names(&vfat).is_empty());
+    assert_eq!(fat_entries(&vfat, net.start()), vec![0, 0]);
+
+    let free = (3..10)
+        .map(|n| fat_entries(&vfat, Cluster::from(n)))
+        .all(|entries| entries == vec![0, 0]);
+    assert!(free);
+}
+
+#[test]
+fn rename_file() {
+    let vfat = mock_vfat();
+    vfat.create_dir("/boot", false).expect("created");
+    let mut file = vfat.create_file("/kernel8.img.new").expect("created");
+    file.write_all(b"new kernel").expect("written");
+
+    vfat.rename("/kernel8.img.new", "/boot/kernel8.img")
+        .expect("renamed");
+    assert_eq!(
+        vfat.open("/kernel8.img.new").unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+    assert_eq!(&read_file(&vfat, "/boot/kernel8.img")[..], b"new kernel");
+
+    vfat.rename("/boot/kernel8.img", "/boot/KERNEL8.IMG")
+        .expect("renamed");
+    let entry = vfat.open("/boot/kernel8.img").expect("entry exists");
+    assert_eq!(entry.name(), "KERNEL8.IMG");
+
+    vfat.create_file("/config.txt").expect("created");
+    vfat.create_dir("/boot/overlays", false).expect("created");
+    let kind = |result: io::Result<()>| result.unwrap_err().kind();
+    assert_eq!(
+        kind(vfat.rename("/config.txt", "/boot/overlays")),
+        io::ErrorKind::AlreadyExists
+    );
+    assert_eq!(kind(vfat.rename("/missing", "/other")), io::ErrorKind::NotFound);
+    assert_eq!(kind(vfat.rename("config.txt", "/other")), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn rename_replaces_file() {
+    let vfat = mock_vfat();
+    vfat.create_dir("/boot", false).expect("created");
+    let mut old = vfat.create_file("/boot/kernel8.img").expect("created");
+    old.write_all(&[1; 1500]).expect("written");
+    drop(old);
+    let mut new = vfat.create_file("/kernel8.img.new").expect("created");
+    new.write_all(b"new kernel").expect("written");
+    let free = vfat.borrow_mut().free_cluster_count().unwrap();
+
+    vfat.rename("/kernel8.img.new", "/boot/KERNEL8.IMG").expect("replaced");
+    assert_eq!(&read_file(&vfat, "/boot/kernel8.img")[..], b"new kernel");
+    assert_eq!(vfat.open("/boot/kernel8.img").expect("entry exists").name(), "kernel8.img");
+    assert_eq!(
+        vfat.open("/kernel8.img.new").unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+    assert_eq!(vfat.borrow_mut().free_cluster_count().unwrap(), free + 3);
+
+    // The moved file's handle follows it to the replaced entry.
+    new.write_all(b"!").expect("written");
+    assert_eq!(&read_file(&vfat, "/boot/kernel8.img")[..], b"new kernel!");
+
+    let names: Vec<String> = vfat
+        .open_dir("/boot")
+        .expect("directory exists")
+        .entries()
+        .expect("entries")
+        .map(|entry| entry.name().to_string())
+        .collect();
+    assert_eq!(names, vec![".", "..", "kernel8.img"]);
+
+    vfat.create_file("/config.txt").expect("created");
+    let kind = |result: io::Result<()>| result.unwrap_err().kind();
+    assert_eq!(kind(vfat.rename("/config.txt", "/boot/kernel8.img")), io::ErrorKind::Other);
+    drop(new);
+    vfat.rename("/config.txt", "/boot/kernel8.img").expect("replaced");
+    assert!(read_file(&vfat, "/boot/kernel8.img").is_empty());
+
+    vfat.create_dir("/boot/overlays", false).expect("created");
+    vfat.create_file("/overlays").expect("created");
+    assert_eq!(kind(vfat.rename("/overlays", "/boot/overlays")), io::ErrorKind::AlreadyExists);
+    assert_eq!(kind(vfat.rename("/boot/overlays", "/overlays")), io::ErrorKind::AlreadyExists);
+}
+
+#[test]
+fn rename_dir() {
+    let vfat = mock_vfat();
+    vfat.create_dir("/config/net", true).expect("created");
+    vfat.create_file("/config/net/interfaces").expect("created");
+    let etc = vfat.create_dir("/etc", false).expect("created");
+
+    let kind = |result: io::Result<()>| result.unwrap_err().kind();
+    assert_eq!(kind(vfat.rename("/config", "/config/net/config")), io::ErrorKind::InvalidInput);
+    assert_eq!(kind(vfat.rename("/config", "/config/config")), io::ErrorKind::InvalidInput);
+
+    vfat.rename("/config/net", "/etc/network").expect("renamed");
+    let network = vfat.open_dir("/etc/network").expect("directory exists");
+    vfat.open_file("/etc/network/interfaces").expect("file exists");
+    let dotdot = network.find("..").expect("dotdot").into_dir().expect("dir");
+    assert_eq!(dotdot.start(), etc.start());
+
+    vfat.rename("/etc/network", "/network").expect("renamed");
+    let network = vfat.open_dir("/network").expect("directory exists");
+    let dotdot = network.find("..").expect("dotdot").into_dir().expect("dir");
+    assert_eq!(dotdot.start(), Cluster::from(0));
+}
+
+#[test]
+fn fs_info_counts() {
+    let vfat = mock_vfat();
+    assert_eq!(vfat.borrow_mut().free_cluster_count().unwrap(), 249);
+    assert_eq!(vfat.borrow().next_free_hint(), 3);
+
+    let mut file = vfat.create_file("/data.bin").expect("created");
+    file.write_all(&[1; 1500]).expect("written");
+    assert_eq!(vfat.borrow_mut().free_cluster_count().unwrap(), 246);
+    assert_eq!(vfat.borrow().next_free_hint(), 6);
+
+    vfat.borrow_mut().sync().expect("synced");
+    let fs_info = {
+        let mut vfat = vfat.borrow_mut();
+        let sector = vfat.fs_info_sector;
+        FsInfo::from(vfat.device_mut(), sector).expect("valid FSInfo")
+    };
+    assert_eq!(fs_info.free_count(), Some(246));
+    assert_eq!(fs_info.next_free(), Some(6));
+
+    drop(file);
+    vfat.remove("/data.bin", false).expect("removed");
+    assert_eq!(vfat.borrow_mut().free_cluster_count().unwrap(), 249);
+}
+
+#[test]
+fn fs_info_invalid() {
+    let mut image = mock_image();
+    put_u32(&mut image, (PARTITION_START + 1) * SECTOR_SIZE + 484, 0);
+    let vfat = VFat::from(Cursor::new(image)).expect("valid mock image");
+    assert!(vfat.borrow().fs_info.is_none());
+    assert_eq!(vfat.borrow_mut().free_cluster_count().unwrap(), 249);
+    assert_eq!(vfat.borrow().next_free_hint(), 2);
+}
+
+#[test]
+fn fs_info_unknown() {
+    let mut image = mock_image();
+    let fs_info = (PARTITION_START + 1) * SECTOR_SIZE;
+    put_u32(&mut image, fs_info + 488, 0xFFFFFFFF);
+    put_u32(&mut image, fs_info + 492, 0xFFFFFFFF);
+    let vfat = VFat::from(Cursor::new(image)).expect("valid mock image");
+    let read_fs_info = || {
+        let mut vfat = vfat.borrow_mut();
+        let sector = vfat.fs_info_sector;
+        FsInfo::from(vfat.device_mut(), sector).expect("valid FSInfo")
+    };
+
+    // Counting the free clusters changes nothing on disk.
+    assert_eq!(vfat.borrow_mut().free_cluster_count().unwrap(), 249);
+    vfat.borrow_mut().sync().expect("synced");
+    assert_eq!(read_fs_info().free_count(), None);
+    assert_eq!(read_fs_info().next_free(), None);
+
+    vfat.create_file("/data.bin")
+        .and_then(|mut file| file.write_all(&[1; 10]))
+        .expect("written");
+    vfat.borrow_mut().sync().expect("synced");
+    assert_eq!(read_fs_info().free_count(), Some(248));
+    assert_eq!(read_fs_info().next_free(), Some(4));
+}
+
+#[test]
+fn active_fat() {
+    let mut image = mock_image();
+    let flags = PARTITION_START * SECTOR_SIZE + 40;
+    image[flags] = 0x81;
+
+    // Corrupt the root directory's entry in the inactive first FAT.
+    put_u32(&mut image, (PARTITION_START + RESERVED_SECTORS) * SECTOR_SIZE + 8, 0x0FFFFFF7);
+
+    let vfat = VFat::from(Cursor::new(image)).expect("valid mock image");
+    let mut file = vfat.create_file("/log.txt").expect("created");
+    file.write_all(&[7; 600]).expect("written");
+    assert_eq!(read_file(&vfat, "/log.txt"), vec![7; 600]);
+
+    assert_eq!(fat_entries(&vfat, Cluster::from(3)), vec![0, 4]);
+    assert_eq!(fat_entries(&vfat, Cluster::from(4)), vec![0, FatEntry::EOC.0]);
+}
+
+#[test]
+fn sync_writes_back() {
+    let image = SharedImage::new(mock_image());
+    let vfat = VFat::from(image.clone()).expect("valid mock image");
+    let mut file = vfat.create_file("/notes.txt").expect("created");
+    file.write_all(b"written through the cache").expect("written");
+
+    let before = image.bytes();
+    let fat = (PARTITION_START + RESERVED_SECTORS) * SECTOR_SIZE;
+    assert_eq!(&before[fat + 12..fat + 16], &[0, 0, 0, 0]);
+
+    file.sync().expect("synced");
+    let after = image.bytes();
+    assert_eq!(&after[fat + 12..fat + 16], &[0xFF, 0xFF, 0xFF, 0x0F]);
+
+    let remounted = VFat::from(Cursor::new(after)).expect("valid image");
+    assert_eq!(read_file(&remounted, "/notes.txt"), b"written through the cache");
+}
+
+#[test]
+fn drop_syncs() {
+    let image = SharedImage::new(mock_image());
+    {
+        let vfat = VFat::from(image.clone()).expect("valid mock image");
+        vfat.create_dir("/a/b", true).expect("created");
+        let mut file = vfat.create_file("/a/b/c").expect("created");
+        file.write_all(&[3; 1000]).expect("written");
+    }
+
+    let remounted = VFat::from(Cursor::new(image.bytes())).expect("valid image");
+    assert_eq!(read_file(&remounted, "/a/b/c"), vec![3; 1000]);
+    assert_eq!(remounted.borrow_mut().free_cluster_count().unwrap(), 245);
+}
+
+#[test]
+fn drop_unchanged_writes_nothing() {
+    let image = SharedImage::new(mock_image());
+    let clock = ManualClock::new(Timestamp::from_unix(1_500_000_000, 0));
+    {
+        let vfat = VFat::from(image.clone()).expect("valid mock image");
+        vfat.borrow_mut().set_clock(clock.clone());
+        let mut file = vfat.create_file("/notes.txt").expect("created");
+        file.write_all(b"read only").expect("written");
+    }
+
+    // With an unknown next free cluster hint, mounting, listing, reading and
+    // dropping leave the device untouched.
+    let mut bytes = image.bytes();
+    put_u32(&mut bytes, (PARTITION_START + 1) * SECTOR_SIZE + 492, 0xFFFFFFFF);
+    let image = LoggedImage::new(bytes);
+    {
+        let vfat = VFat::from(image.clone()).expect("valid image");
+        vfat.borrow_mut().set_clock(clock.clone());
+        assert_eq!(root_names(&vfat), vec!["notes.txt"]);
+        assert_eq!(read_file(&vfat, "/notes.txt"), b"read only");
+        vfat.borrow_mut().free_cluster_count().unwrap();
+    }
+
+    assert!(image.take_log().iter().all(|&(write, _, _)| !write));
+}
+
+#[test]
+fn cache_flush_splits_logical_sectors() {
+    let image = SharedImage::new(vec![0; 8 * SECTOR_SIZE]);
+    let partition = Partition {
+        start: 2,
+        sector_size: 2 * SECTOR_SIZE as u64,
+    };
+    let mut device = CachedDevice::new(image.clone(), partition);
+
+    let mut logical = vec![0; 2 * SECTOR_SIZE];
+    logical[..SECTOR_SIZE].copy_from_slice(&[1; SECTOR_SIZE]);
+    logical[SECTOR_SIZE..].copy_from_slice(&[2; SECTOR_SIZE]);
+    device.write_sector(3, &logical).expect("written");
+    device.write_sector(0, &[9; SECTOR_SIZE]).expect("written");
+    assert_eq!(image.bytes(), vec![0; 8 * SECTOR_SIZE]);
+
+    device.flush().expect("flushed");
+    let bytes = image.bytes();
+    assert_eq!(&bytes[..SECTOR_SIZE], &[9; SECTOR_SIZE][..]);
+    assert_eq!(&bytes[SECTOR_SIZE..4 * SECTOR_SIZE], &[0; 3 * SECTOR_SIZE][..]);
+    assert_eq!(&bytes[4 * SECTOR_SIZE..5 * SECTOR_SIZE], &[1; SECTOR_SIZE][..]);
+    assert_eq!(&bytes[5 * SECTOR_SIZE..6 * SECTOR_SIZE], &[2; SECTOR_SIZE][..]);
+    assert_eq!(&bytes[6 * SECTOR_SIZE..], &[0; 2 * SECTOR_SIZE][..]);
+}
+
+#[test]
+fn cache_evicts_least_recently_used() {
+    let image = SharedImage::new(vec![0; 4 * SECTOR_SIZE]);
+    let partition = Partition {
+        start: 0,
+        sector_size: SECTOR_SIZE as u64,
+    };
+    let mut device = CachedDevice::with_capacity(image.clone(), partition, 2);
+    device.set_read_ahead(0);
+
+    device.write_sector(0, &[1; SECTOR_SIZE]).expect("written");
+    device.write_sector(1, &[2; SECTOR_SIZE]).expect("written");
+    device.get(0).expect("cached");
+    assert_eq!(image.bytes(), vec![0; 4 * SECTOR_SIZE]);
+
+    // Sector 1 is the least recently used, so reading sector 2 writes it back
+    // and evicts it.
+    device.get(2).expect("read");
+    let bytes = image.bytes();
+    assert_eq!(&bytes[..SECTOR_SIZE], &[0; SECTOR_SIZE][..]);
+    assert_eq!(&bytes[SECTOR_SIZE..2 * SECTOR_SIZE], &[2; SECTOR_SIZE][..]);
+
+    let stats = device.stats();
+    assert_eq!((stats.hits, stats.misses, stats.evictions), (1, 3, 1));
+    assert_eq!((stats.bytes_read, stats.bytes_written), (3 * 512, 512));
+    assert_eq!((stats.dirty_sectors, stats.data_sectors), (1, 2));
+
+    device.set_capacity(1).expect("shrunk");
+    assert_eq!(&image.bytes()[..SECTOR_SIZE], &[1; SECTOR_SIZE][..]);
+
+    let mut buf = [0; SECTOR_SIZE];
+    device.read_sector(1, &mut buf).expect("read");
+    assert_eq!(&buf[..], &[2; SECTOR_SIZE][..]);
+    assert_eq!(device.set_capacity(0).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn small_cache() {
+    let image = SharedImage::new(mock_image());
+    {
+        let vfat = VFat::from(image.clone()).expect("valid mock image");
+        vfat.borrow_mut().set_cache_capacity(2).expect("shrunk");
+        assert_eq!(vfat.borrow().cache_capacity(), 2);
+
+        vfat.create_dir("/a/b", true).expect("created");
+        let mut file = vfat.create_file("/a/b/c").expect("created");
+        file.write_all(&[3; 1000]).expect("written");
+        assert_eq!(read_file(&vfat, "/a/b/c"), vec![3; 1000]);
+    }
+
+    let remounted = VFat::from(Cursor::new(image.bytes())).expect("valid image");
+    assert_eq!(read_file(&remounted, "/a/b/c"), vec![3; 1000]);
+    assert_eq!(remounted.borrow_mut().free_cluster_count().unwrap(), 245);
+}
+
+#[test]
+fn cache_read_ahead() {
+    let image = SharedImage::new((0..32).flat_map(|i| vec![i as u8; SECTOR_SIZE]).collect());
+    let partition = Partition {
+        start: 0,
+        sector_size: SECTOR_SIZE as u64,
+    };
+    let mut device = CachedDevice::with_capacity(image.clone(), partition, 32);
+    device.set_read_ahead(4);
+    device.get_mut(9).expect("cached")[0] = 0xFF;
+
+    // The second of two consecutive misses reads four more sectors ahead.
+    device.get(0).expect("read");
+    assert_eq!(device.stats().prefetched, 0);
+    device.get(1).expect("read");
+    assert_eq!(device.stats().prefetched, 4);
+    for sector in 2..6 {
+        assert_eq!(device.get(sector).expect("read")[0], sector as u8);
+    }
+    let stats = device.stats();
+    assert_eq!((stats.hits, stats.misses), (4, 3));
+
+    // Read-ahead stops at a cached sector without overwriting it, and the run
+    // continues past it.
+    device.get(6).expect("read");
+    assert_eq!(device.stats().prefetched, 6);
+    assert_eq!(device.get(9).expect("cached")[0], 0xFF);
+    device.get(10).expect("read");
+    assert_eq!(device.stats().prefetched, 10);
+
+    // A non-sequential miss reads nothing ahead, and neither does a cache
+    // with read-ahead turned off.
+    device.get(20).expect("read");
+    device.set_read_ahead(0);
+    device.get(21).expect("read");
+    assert_eq!(device.stats().prefetched, 10);
+
+    // Read-ahead stops at the end of the device.
+    device.set_read_ahead(4);
+    device.get(29).expect("read");
+    device.get(30).expect("read");
+    assert_eq!(device.stats().prefetched, 11);
+}
+
+#[test]
+fn read_write_sectors() {
+    let data: Vec<u8> = (0..4).flat_map(|i| vec![i as u8; SECTOR_SIZE]).collect();
+    let mut cursor = Cursor::new(data.clone());
+    let mut shared = SharedImage::new(data.clone());
+
+    let mut buf = vec![0; 2 * SECTOR_SIZE];
+    assert_eq!(cursor.read_sectors(1, 2, &mut buf).unwrap(), 2 * SECTOR_SIZE);
+    assert_eq!(&buf[..], &data[SECTOR_SIZE..3 * SECTOR_SIZE]);
+    buf = vec![0; 2 * SECTOR_SIZE];
+    assert_eq!(shared.read_sectors(1, 2, &mut buf).unwrap(), 2 * SECTOR_SIZE);
+    assert_eq!(&buf[..], &data[SECTOR_SIZE..3 * SECTOR_SIZE]);
+
+    let ones = vec![1; 3 * SECTOR_SIZE];
+    assert_eq!(cursor.write_sectors(0, 3, &ones).unwrap(), 3 * SECTOR_SIZE);
+    assert_eq!(shared.write_sectors(0, 3, &ones).unwrap(), 3 * SECTOR_SIZE);
+    assert_eq!(&cursor.get_ref()[..3 * SECTOR_SIZE], &ones[..]);
+    assert_eq!(&shared.bytes()[..3 * SECTOR_SIZE], &ones[..]);
+
+    let kind = |result: io::Result<usize>| result.unwrap_err().kind();
+    assert_eq!(kind(cursor.read_sectors(0, 3, &mut buf)), io::ErrorKind::InvalidInput);
+    assert_eq!(kind(shared.write_sectors(0, 3, &buf)), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn cache_batches_device_access() {
+    let image = LoggedImage::new((0..16).flat_map(|i| vec![i as u8; SECTOR_SIZE]).collect());
+    let partition = Partition {
+        start: 4,
+        sector_size: 2 * SECTOR_SIZE as u64,
+    };
+    let mut device = CachedDevice::new(image.clone(), partition);
+    device.set_read_ahead(0);
+
+    // Logical sectors 5 and 6 are physical sectors 6 through 9, of which 7 is
+    // already cached.
+    device.get(7).expect("read");
+    image.take_log();
+    let mut buf = vec![0; 4 * SECTOR_SIZE];
+    assert_eq!(device.read_sectors(5, 2, &mut buf).unwrap(), 4 * SECTOR_SIZE);
+    let expected: Vec<u8> = (6..10).flat_map(|i| vec![i as u8; SECTOR_SIZE]).collect();
+    assert_eq!(buf, expected);
+    assert_eq!(image.take_log(), vec![(false, 6, 1), (false, 8, 2)]);
+
+    device.read_sectors(5, 2, &mut buf).expect("read");
+    assert_eq!(image.take_log(), vec![]);
+
+    for &sector in &[1, 2, 3, 8, 10] {
+        device.get_mut(sector).expect("cached")[0] = 0xFF;
+    }
+    image.take_log();
+    device.flush().expect("flushed");
+    assert_eq!(
+        image.take_log(),
+        vec![(true, 1, 3), (true, 8, 1), (true, 10, 1)]
+    );
+}
+
+#[test]
+fn cache_stats() {
+    let vfat = mock_vfat();
+    vfat.borrow_mut().set_read_ahead(0);
+    vfat.create_dir("/logs", false).expect("created");
+    let mut file = vfat.create_file("/logs/boot.log").expect("created");
+    file.write_all(&[7; 1000]).expect("written");
+
+    let stats = vfat.borrow().cache_stats();
+    assert_eq!(stats.reserved_sectors, 1);
+    assert_eq!(stats.fat_sectors, 2);
+    assert_eq!(stats.directory_sectors, 2);
+    assert_eq!(stats.data_sectors, 2);
+    assert!(stats.dirty_sectors > 0);
+    assert_eq!(stats.bytes_written, 0);
+
+    vfat.borrow_mut().sync().expect("synced");
+    let stats = vfat.borrow().cache_stats();
+    assert_eq!(stats.dirty_sectors, 0);
+    assert!(stats.bytes_written > 0);
+
+    vfat.borrow_mut().reset_cache_stats();
+    assert_eq!(read_file(&vfat, "/logs/boot.log"), vec![7; 1000]);
+    let stats = vfat.borrow().cache_stats();
+    assert!(stats.hits > 0);
+    assert_eq!((stats.misses, stats.bytes_read, stats.bytes_written), (0, 0, 0));
+    assert_eq!(stats.directory_sectors, 2);
+}
+
+#[test]
+fn invalid_lfn_entries() {
+    let image = SharedImage::new(mock_image());
+    {
+        let vfat = VFat::from(image.clone()).expect("valid mock image");
+        vfat.create_file("/long name.txt").expect("created");
+    }
+
+    let root = (PARTITION_START + RESERVED_SECTORS + 2 * SECTORS_PER_FAT) * SECTOR_SIZE;
+    let pristine = image.bytes();
+    assert_eq!(pristine[root], 0x41);
+
+    let remounted = VFat::from(Cursor::new(pristine.clone())).expect("valid image");
+    assert_eq!(root_names(&remounted), vec!["long name.txt"]);
+
+    // A bad checksum, a missing last-entry marker, and a sequence that does
+    // not count down to 1 each leave the LFN entry orphaned.
+    for &(offset, value) in &[(13, 0x00), (0, 0x01), (0, 0x42)] {
+        let mut image = pristine.clone();
+        image[root + offset] = value;
+        let remounted = VFat::from(Cursor::new(image)).expect("valid image");
+        assert_eq!(root_names(&remounted), vec!["LONGNA~1.TXT"]);
+    }
+
+    // An orphaned LFN entry preceding a valid sequence is ignored.
+    let mut image = pristine.clone();
+    let entries = pristine[root..root + 64].to_vec();
+    image[root + 32..root + 96].copy_from_slice(&entries);
+    image[root + 13] ^= 0xFF;
+    let remounted = VFat::from(Cursor::new(image)).expect("valid image");
+    assert_eq!(root_names(&remounted), vec!["long name.txt"]);
+}
+
+#[test]
+fn dir_entries_span_clusters() {
+    let image = SharedImage::new(mock_image());
+    let mut names: Vec<String> = (0..7).map(|i| format!("file{}", i)).collect();
+    names.push("a longer file name".to_string());
+    {
+        let vfat = VFat::from(image.clone()).expect("valid mock image");
+        for name in names.iter() {
+            vfat.create_file(format!("/{}", name)).expect("created");
+        }
+
+        // The last name's two LFN entries and its regular entry occupy slots
+        // 14 through 16, straddling the root directory's two clusters.
+        let root = vfat.borrow().root_dir_cluster;
+        let chain = vfat.borrow_mut().chain(root).unwrap();
+        assert_eq!(chain, vec![Cluster::from(2), Cluster::from(3)]);
+        assert_eq!(root_names(&vfat), names);
+    }
+
+    // Entries past the end-of-directory marker in slot 17 are never read.
+    let root = (PARTITION_START + RESERVED_SECTORS + 2 * SECTORS_PER_FAT) * SECTOR_SIZE;
+    let mut image = image.bytes();
+    let entries = image[root..root + 64].to_vec();
+    image[root + 18 * 32..root + 20 * 32].copy_from_slice(&entries);
+    let remounted = VFat::from(Cursor::new(image)).expect("valid image");
+    assert_eq!(root_names(&remounted), names);
+}
+
+#[test]
+fn dir_entries_report_bad_chains() {
+    let vfat = mock_vfat();
+    let mut names: Vec<String> = (0..7).map(|i| format!("file{}", i)).collect();
+    names.push("a longer file name".to_string());
+    for name in names.iter() {
+        vfat.create_file(format!("/{}", name)).expect("created");
+    }
+
+    // The root directory's second cluster now claims to be free.
+    vfat.borrow_mut()
+        .set_fat_entry(Cluster::from(3), FatEntry::FREE)
+        .expect("FAT entry written");
+
+    let root = vfat.open_dir("/").expect("root directory");
+    let mut iter = root.entries().expect("first cluster read");
+    let listed: Vec<String> = iter.by_ref().map(|entry| entry.name().to_string()).collect();
+    assert_eq!(listed, &names[..7]);
+    assert_eq!(iter.take_error().expect("error kept").kind(), io::ErrorKind::InvalidData);
+    assert!(iter.next().is_none());
+    assert!(iter.take_error().is_none());
+
+    let mut iter = root.entries().expect("first cluster read");
+    for _ in 0..7 {
+        assert!(iter.try_next().expect("entry read").is_some());
+    }
+    assert_eq!(iter.try_next().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert!(iter.try_next().expect("iteration ended").is_none());
+
+    assert_eq!(
+        vfat.open("/a longer file name").unwrap_err().kind(),
+        io::ErrorKind::InvalidData
+    );
+}
+
+#[test]
+fn set_len_shrink() {
+    let vfat = mock_vfat();
+    let data: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+    let mut file = vfat.create_file("/app.log").expect("created");
+    file.write_all(&data).expect("written");
+    assert_eq!(vfat.borrow_mut().free_cluster_count().unwrap(), 246);
+
+    file.set_len(600).expect("truncated");
+    assert_eq!(file.size(), 600);
+    assert_eq!(read_file(&vfat, "/app.log"), &data[..600]);
+    assert_eq!(fat_entries(&vfat, Cluster::from(4)), vec![FatEntry::EOC.0; 2]);
+    assert_eq!(fat_entries(&vfat, Cluster::from(5)), vec![0, 0]);
+    assert_eq!(vfat.borrow_mut().free_cluster_count().unwrap(), 247);
+
+    file.set_len(0).expect("truncated");
+    assert_eq!(read_file(&vfat, "/app.log"), Vec::<u8>::new());
+    assert_eq!(fat_entries(&vfat, Cluster::from(3)), vec![0, 0]);
+    assert_eq!(vfat.borrow_mut().free_cluster_count().unwrap(), 249);
+
+    let mut file = vfat.open_file("/app.log").expect("file exists");
+    file.write_all(b"rotated").expect("written");
+    assert_eq!(read_file(&vfat, "/app.log"), b"rotated");
+}
+
+#[test]
+fn set_len_extend() {
+    let vfat = mock_vfat();
+    let mut file = vfat.create_file("/app.log").expect("created");
+    file.write_all(&[0xAA; 700]).expect("written");
+    file.set_len(100).expect("truncated");
+
+    file.set_len(400).expect("extended");
+    let mut expected = vec![0xAA; 100];
+    expected.resize(400, 0);
+    assert_eq!(read_file(&vfat, "/app.log"), expected);
+
+    let mut empty = vfat.create_file("/empty").expect("created");
+    empty.set_len(1200).expect("extended");
+    assert_eq!(read_file(&vfat, "/empty"), vec![0; 1200]);
+    assert_eq!(vfat.borrow_mut().chain(Cluster::from(5)).unwrap().len(), 3);
+
+    assert!(file.set_len(1 << 32).is_err());
+}
+
+#[test]
+fn grow_out_of_space() {
+    let vfat = mock_vfat();
+    let free = vfat.borrow_mut().free_cluster_count().unwrap() as usize;
+    let too_big = (free + 1) * SECTOR_SIZE;
+
+    let mut file = vfat.create_file("/empty.bin").expect("created");
+    let err = file.write_all(&vec![1; too_big]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+    assert_eq!(vfat.borrow_mut().free_cluster_count().unwrap() as usize, free);
+    assert_eq!(file.set_len(too_big as u64).unwrap_err().kind(), io::ErrorKind::Other);
+    assert_eq!(vfat.borrow_mut().free_cluster_count().unwrap() as usize, free);
+    assert_eq!(file.size(), 0);
+
+    file.write_all(&[2; 1000]).expect("written");
+    file.seek(SeekFrom::Start(0)).expect("seeked");
+    assert!(file.write_all(&vec![3; too_big]).is_err());
+    assert!(file.set_len(too_big as u64).is_err());
+    assert_eq!(vfat.borrow_mut().free_cluster_count().unwrap() as usize, free - 2);
+
+    assert_eq!(read_file(&vfat, "/empty.bin"), vec![2; 1000]);
+}
+
+#[test]
+fn seek_past_end() {
+    let vfat = mock_vfat();
+    let mut file = vfat.create_file("/sparse").expect("created");
+    assert_eq!(file.seek(SeekFrom::End(0)).expect("seek"), 0);
+    assert_eq!(file.seek(SeekFrom::Start(0)).expect("seek"), 0);
+
+    let mut buf = [0; 8];
+    assert_eq!(file.seek(SeekFrom::Start(100)).expect("seek"), 100);
+    assert_eq!(file.read(&mut buf).expect("read"), 0);
+    assert_eq!(file.size(), 0);
+
+    file.write_all(b"head").expect("written");
+    assert_eq!(file.seek(SeekFrom::Current(900)).expect("seek"), 1004);
+    file.write_all(b"tail").expect("written");
+    assert_eq!(file.size(), 1008);
+
+    let mut expected = vec![0; 100];
+    expected.extend_from_slice(b"head");
+    expected.resize(1004, 0);
+    expected.extend_from_slice(b"tail");
+    assert_eq!(read_file(&vfat, "/sparse"), expected);
+
+    assert_eq!(file.seek(SeekFrom::End(-4)).expect("seek"), 1004);
+    assert_eq!(file.read(&mut buf).expect("read"), 4);
+    assert_eq!(&buf[..4], b"tail");
+    assert_eq!(file.read(&mut buf).expect("read"), 0);
+
+    assert!(file.seek(SeekFrom::Current(-2000)).is_err());
+    assert_eq!(file.seek(SeekFrom::Current(0)).expect("seek"), 1008);
+}
+
+#[test]
+fn read_fragmented_file() {
+    let vfat = mock_vfat();
+    let data: Vec<u8> = (0..1800).map(|i| (i % 251) as u8).collect();
+
+    let mut file = vfat.create_file("/fragmented").expect("created");
+    let mut other = vfat.create_file("/other").expect("created");
+    for (i, chunk) in data.chunks(SECTOR_SIZE).enumerate() {
+        file.write_all(chunk).expect("written");
+        other.write_all(&[i as u8; SECTOR_SIZE]).expect("written");
+    }
+
+    let chain = vfat.borrow_mut().chain(Cluster::from(3)).unwrap();
+    assert_eq!(chain, vec![3, 5, 7, 9].into_iter().map(Cluster::from).collect::<Vec<_>>());
+    assert_eq!(read_file(&vfat, "/fragmented"), data);
+
+    let mut file = vfat.open_file("/fragmented").expect("file exists");
+    let mut buf = [0; 100];
+    for &pos in &[1700usize, 480, 1030, 0] {
+        file.seek(SeekFrom::Start(pos as u64)).expect("seek");
+        file.read_exact(&mut buf).expect("read");
+        assert_eq!(&buf[..], &data[pos..pos + 100]);
+    }
+}
+
+#[test]
+fn read_whole_clusters_directly() {
+    let image = LoggedImage::new(mock_image());
+    let vfat = VFat::from(image.clone()).expect("valid mock image");
+    let data: Vec<u8> = (0..2000).map(|i| (i % 251) as u8).collect();
+    let mut file = vfat.create_file("/kernel.img").expect("created");
+    file.write_all(&data).expect("written");
+    vfat.borrow_mut().sync().expect("synced");
+
+    // An unwritten change to the second cluster is seen by direct reads.
+    let mut file = vfat.open_file("/kernel.img").expect("file exists");
+    file.seek(SeekFrom::Start(600)).expect("seek");
+    file.write_all(&[0xEE; 10]).expect("written");
+    let mut expected = data.clone();
+    for byte in expected[600..610].iter_mut() {
+        *byte = 0xEE;
+    }
+
+    // The first three of the file's four contiguous clusters are read with a
+    // single read straight from the device; the partial last cluster goes
+    // through the cache.
+    let mut file = vfat.open_file("/kernel.img").expect("file exists");
+    vfat.borrow_mut().reset_cache_stats();
+    image.take_log();
+    let mut buf = vec![0; 2000];
+    file.read_exact(&mut buf).expect("read");
+    assert_eq!(buf, expected);
+
+    let data_start = (PARTITION_START + RESERVED_SECTORS + 2 * SECTORS_PER_FAT) as u64;
+    let log = image.take_log();
+    assert_eq!(log[0], (false, data_start + 1, 3));
+    assert!(log[1..].iter().all(|&(_, sector, _)| sector != data_start + 2));
+    assert_eq!(vfat.borrow().cache_stats().bypassed, 3);
+}
+
+#[test]
+fn concurrent_readers() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Shared<VFat>>();
+
+    let vfat = mock_vfat();
+    let clock = ManualClock::new(Timestamp::from_calendar(2024, 5, 6, 7, 8, 10, 0).unwrap());
+    vfat.borrow_mut().set_clock(clock.clone());
+    let data: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+    for i in 0..4 {
+        let mut file = vfat.create_file(format!("/file{}", i)).expect("created");
+        file.write_all(&data).expect("written");
+    }
+
+    // Threads list and read while this one holds a shared borrow. The reads
+    // are a day after the writes, so each records a new accessed date.
+    clock.set(Timestamp::from_calendar(2024, 5, 7, 9, 0, 0, 0).unwrap());
+    let guard = vfat.borrow();
+    let (sender, receiver) = mpsc::channel();
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            let (vfat, data, sender) = (vfat.clone(), data.clone(), sender.clone());
+            thread::spawn(move || {
+                assert_eq!(root_names(&vfat).len(), 4);
+                assert_eq!(read_file(&vfat, &format!("/file{}", i)), data);
+                sender.send(i).unwrap();
+            })
+        }).collect();
+
+    for _ in 0..4 {
+        receiver
+            .recv_timeout(Duration::from_secs(10))
+            .expect("reader finished while the file system was borrowed");
+    }
+    drop(guard);
+
+    for handle in handles {
+        handle.join().expect("reader succeeded");
+    }
+
+    vfat.borrow_mut().sync().expect("synced");
+    let file = vfat.open("/file0").expect("file exists");
+    assert_eq!(file.metadata().accessed.day(), 7);
+}
+
+#[test]
+fn concurrent_creates() {
+    let vfat = mock_vfat();
+    for round in 0..8 {
+        vfat.create_dir(format!("/round{}", round), false).expect("created");
+    }
+
+    // Every thread tries to create the same names, so each name's check for
+    // conflicts races with the others' writes. The names share a short name
+    // prefix, so the numeric tails race as well.
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let (vfat, barrier) = (vfat.clone(), barrier.clone());
+            thread::spawn(move || {
+                let mut results = vec![];
+                for round in 0..8 {
+                    barrier.wait();
+                    for i in 0..32 {
+                        let path = format!("/round{}/Shared name {}.txt", round, i);
+                        results.push(match i % 4 {
+                            3 => vfat.create_dir(&path, false).map(|_| ()),
+                            _ => vfat.create_file(&path).map(|_| ()),
+                        });
+                    }
+                }
+                results
+            })
+        }).collect();
+
+    let results: Vec<_> = handles
+        .into_iter()
+        .map(|handle| handle.join().expect("creator finished"))
+        .collect();
+    for i in 0..8 * 32 {
+        let created = results.iter().filter(|results| results[i].is_ok()).count();
+        assert_eq!(created, 1, "name {} created once", i);
+        for result in results.iter() {
+            if let Err(ref e) = result[i] {
+                assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+            }
+        }
+    }
+
+    for round in 0..8 {
+        let mut names: Vec<_> = vfat
+            .open_dir(format!("/round{}", round))
+            .expect("round directory")
+            .entries()
+            .expect("round entries")
+            .map(|entry| entry.name().to_string())
+            .filter(|name| name != "." && name != "..")
+            .collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), 32);
+    }
+}
+
+#[test]
+fn open_file_handles_share_state() {
+    let vfat = mock_vfat();
+    let mut writer = vfat.create_file("/log.txt").expect("created");
+    let mut reader = vfat.open_file("/log.txt").expect("file exists");
+    assert_eq!(reader.size(), 0);
+
+    writer.write_all(&[7; 700]).expect("written");
+    assert_eq!(reader.size(), 700);
+    let mut data = vec![];
+    reader.read_to_end(&mut data).expect("read");
+    assert_eq!(data, vec![7; 700]);
+    assert_eq!(reader.metadata().size, 700);
+
+    writer.set_len(100).expect("truncated");
+    writer.seek(SeekFrom::Start(100)).expect("seeked");
+    writer.write_all(&[9; 500]).expect("written");
+    reader.seek(SeekFrom::Start(0)).expect("seeked");
+    let mut data = vec![];
+    reader.read_to_end(&mut data).expect("read");
+    assert_eq!(&data[..100], &[7; 100][..]);
+    assert_eq!(&data[100..], &[9; 500][..]);
+
+    vfat.rename("/log.txt", "/old.log").expect("renamed");
+    writer.write_all(b"end").expect("written");
+    assert_eq!(reader.size(), 603);
+    assert_eq!(vfat.open_file("/old.log").expect("file exists").size(), 603);
+    vfat.create_file("/log.txt").expect("created");
+    assert_eq!(reader.size(), 603);
+
+    let mut attributes = reader.metadata().attributes;
+    attributes.set_read_only(true);
+    vfat.set_attributes("/old.log", attributes).expect("set");
+    reader.seek(SeekFrom::Start(0)).expect("seeked");
+    reader.read(&mut [0; 1]).expect("read");
+    assert!(reader.metadata().read_only());
+}
+
+#[test]
+fn stale_handle_after_slot_reuse() {
+    // With a fixed clock, the new file's entry matches the old one in every
+    // timestamp, and it is written to the same slots.
+    let vfat = mock_vfat();
+    let now = Timestamp::from_calendar(2024, 5, 6, 7, 8, 10, 0).unwrap();
+    vfat.borrow_mut().set_clock(FixedClock(now));
+    vfat.create_file("/a.txt")
+        .and_then(|mut file| file.write_all(b"AAAAAAAA"))
+        .expect("written");
+    let mut stale = vfat.open("/a.txt").expect("file exists").into_file().expect("a file");
+
+    vfat.remove("/a.txt", false).expect("removed");
+    vfat.create_file("/b.txt")
+        .and_then(|mut file| file.write_all(b"BBBBBBBB"))
+        .expect("written");
+
+    let mut buf = vec![];
+    let err = stale.read_to_end(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    assert_eq!(err.to_string(), "a.txt: file was removed");
+    assert_eq!(stale.write(b"XX").unwrap_err().kind(), io::ErrorKind::NotFound);
+    assert_eq!(stale.try_lock().unwrap_err().kind(), io::ErrorKind::NotFound);
+    assert_eq!(read_file(&vfat, "/b.txt"), b"BBBBBBBB".to_vec());
+
+    // Nor can it join the new file's entry while that is open.
+    let _open = vfat.open_file("/b.txt").expect("file exists");
+    assert_eq!(stale.read(&mut buf).unwrap_err().kind(), io::ErrorKind::NotFound);
+}
+
+#[test]
+fn remove_open_file() {
+    let vfat = mock_vfat();
+    vfat.create_dir("/var/log", true).expect("created");
+    vfat.create_dir("/var/log/old", false).expect("created");
+    vfat.create_file("/var/log/auth.log")
+        .and_then(|mut file| file.write_all(&[2; 600]))
+        .expect("written");
+    let mut file = vfat.create_file("/var/log/syslog").expect("created");
+    file.write_all(&[1; 1500]).expect("written");
+    let free = vfat.borrow_mut().free_cluster_count().unwrap();
+
+    let err = vfat.remove("/var/log/syslog", false).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+    assert_eq!(err.to_string(), "syslog: file is open");
+    assert_eq!(
+        vfat.remove("/var", true).unwrap_err().kind(),
+        io::ErrorKind::Other
+    );
+    assert_eq!(vfat.borrow_mut().free_cluster_count().unwrap(), free);
+    assert_eq!(read_file(&vfat, "/var/log/syslog"), vec![1; 1500]);
+    assert_eq!(read_file(&vfat, "/var/log/auth.log"), vec![2; 600]);
+    vfat.open_dir("/var/log/old").expect("directory exists");
+
+    let other = vfat.open_file("/var/log/syslog").expect("file exists");
+    drop(file);
+    assert!(vfat.remove("/var/log/syslog", false).is_err());
+    drop(other);
+    vfat.remove("/var", true).expect("removed recursively");
+    assert_eq!(vfat.borrow_mut().free_cluster_count().unwrap(), free + 8);
+}
+
+#[test]
+fn advisory_locks() {
+    let vfat = mock_vfat();
+    let mut first = vfat.create_file("/lock").expect("created");
+    let mut second = vfat.open_file("/lock").expect("file exists");
+    assert!(!first.is_locked());
+
+    first.try_lock().expect("locked");
+    first.try_lock().expect("locked again by the same handle");
+    assert!(second.is_locked());
+    assert_eq!(second.try_lock().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+    // Locks are advisory and do not prevent other handles from writing.
+    second.write_all(b"data").expect("written");
+
+    second.unlock();
+    assert!(first.is_locked());
+    first.unlock();
+    assert!(!second.is_locked());
+
+    second.try_lock().expect("locked");
+    drop(second);
+    assert!(!first.is_locked());
+    first.try_lock().expect("locked");
+}
+
+#[test]
+fn listed_files_are_not_open() {
+    let vfat = mock_vfat();
+    vfat.create_dir("/tmp", false).expect("created");
+    for name in &["a.txt", "b.txt"] {
+        let mut file = vfat.create_file(format!("/tmp/{}", name)).expect("created");
+        file.write_all(name.as_bytes()).expect("written");
+    }
+
+    let entries: Vec<_> = vfat.open_dir("/tmp").unwrap().entries().unwrap().collect();
+    assert_eq!(entries.len(), 4, "two files and the dot entries");
+    vfat.remove("/tmp/a.txt", false).expect("removed");
+    let mut stale = vfat.open("/tmp/b.txt").unwrap().into_file().unwrap();
+    vfat.remove("/tmp", true).expect("removed recursively");
+
+    let err = stale.read(&mut [0; 4]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    assert_eq!(err.to_string(), "b.txt: file was removed");
+    drop(entries);
+}
+
+#[test]
+fn drop_file_while_borrowed() {
+    let vfat = mock_vfat();
+    let file = vfat.create_file("/held").expect("created");
+    let listed = vfat.open("/held").unwrap().into_file().unwrap();
+
+    let guard = vfat.borrow_mut();
+    drop(file);
+    drop(listed);
+    drop(guard);
+    vfat.remove("/held", false).expect("removed");
+}
+
+#[test]
+fn timestamps() {
+    let vfat = mock_vfat();
+    let clock = ManualClock::new(Timestamp::from_unix(1_500_000_000, 250_000_000));
+    vfat.borrow_mut().set_clock(clock.clone());
+
+    let mut file = vfat.create_file("/events.log").expect("created");
+    let created = file.metadata().created();
+    assert_eq!(
+        (created.year(), created.month(), created.day()),
+        (2017, 7, 14)
+    );
+    assert_eq!((created.hour(), created.minute(), created.second()), (2, 40, 0));
+    assert_eq!(created.centiseconds, 25);
+
+    let metadata = vfat.open_file("/events.log").expect("file exists").metadata().clone();
+    assert_eq!(metadata.created, created);
+    assert_eq!(metadata.modified.time, created.time);
+    assert_eq!(metadata.accessed.date, created.date);
+
+    clock.set(Timestamp::from_unix(1_500_000_000 + 86_400 + 3, 0));
+    file.write_all(b"rotated").expect("written");
+    let metadata = vfat.open_file("/events.log").expect("file exists").metadata().clone();
+    assert_eq!(metadata.created, created);
+    assert_eq!(metadata.modified.day(), 15);
+    assert_eq!(metadata.modified.second(), 2);
+    assert_eq!(metadata.accessed.day(), 15);
+    assert_eq!(file.metadata().modified, metadata.modified);
+
+    clock.set(Timestamp::from_unix(1_500_000_000 + 3 * 86_400, 0));
+    read_file(&vfat, "/events.log");
+    let metadata = vfat.open_file("/events.log").expect("file exists").metadata().clone();
+    assert_eq!(metadata.modified.day(), 15);
+    assert_eq!(metadata.accessed.day(), 17);
+
+    vfat.create_dir("/logs", false).expect("created");
+    let metadata = vfat.open_dir("/logs").expect("directory exists").metadata().clone();
+    assert_eq!(metadata.created.day(), 17);
+}
+
+#[test]
+fn accessed_date_is_written_on_sync() {
+    let vfat = mock_vfat();
+    let clock = ManualClock::new(Timestamp::from_unix(1_500_000_000, 0));
+    vfat.borrow_mut().set_clock(clock.clone());
+    for path in &["/a.txt", "/b.txt"] {
+        vfat.create_file(path)
+            .and_then(|mut file| file.write_all(b"data"))
+            .expect("written");
+    }
+    vfat.borrow_mut().sync().expect("synced");
+
+    clock.set(Timestamp::from_unix(1_500_000_000 + 86_400, 0));
+    let mut file = vfat.open_file("/a.txt").expect("file exists");
+    file.read(&mut [0; 4]).expect("read");
+    assert_eq!(file.metadata().accessed.day(), 15);
+    assert_eq!(vfat.borrow().cache_stats().dirty_sectors, 0);
+    let listed = vfat.open("/a.txt").expect("file exists");
+    assert_eq!(listed.metadata().accessed.day(), 14);
+
+    // A recorded date is dropped when the file is removed, and not given to
+    // a file later created in its place.
+    read_file(&vfat, "/b.txt");
+    vfat.remove("/b.txt", false).expect("removed");
+    clock.set(Timestamp::from_unix(1_500_000_000, 0));
+    vfat.create_file("/b.txt").expect("created");
+    let metadata = vfat.open_file("/b.txt").expect("file exists").metadata().clone();
+    assert_eq!(metadata.accessed.day(), 14);
+
+    // The date recorded by a closed handle follows a rename.
+    drop(file);
+    vfat.rename("/a.txt", "/c.txt").expect("renamed");
+    vfat.borrow_mut().sync().expect("synced");
+    let listed = vfat.open("/c.txt").expect("file exists");
+    assert_eq!(listed.metadata().accessed.day(), 15);
+}
+
+#[test]
+fn set_attributes() {
+    let vfat = mock_vfat();
+    vfat.create_dir("/etc", false).expect("created");
+    let mut file = vfat.create_file("/etc/passwd").expect("created");
+
+    let mut attributes = Attributes::from_raw(0);
+    attributes.set_read_only(true);
+    attributes.set_hidden(true);
+    attributes.set_system(true);
+    vfat.set_attributes("/etc/passwd", attributes).expect("set");
+    let metadata = vfat.open_file("/etc/passwd").expect("file exists").metadata().clone();
+    assert_eq!(metadata.attributes.as_raw(), 0x07);
+    assert!(metadata.read_only() && metadata.hidden());
+
+    file.write_all(b"root").expect("written");
+    let metadata = vfat.open_file("/etc/passwd").expect("file exists").metadata().clone();
+    assert_eq!(metadata.attributes.as_raw(), 0x27);
+
+    attributes.set_archive(false);
+    vfat.set_attributes("/etc/passwd", attributes).expect("set");
+    file.set_len(2).expect("truncated");
+    assert!(vfat.open_file("/etc/passwd").expect("file exists").metadata().attributes.archive());
+
+    let mut attributes = vfat.open_dir("/etc").expect("directory exists").metadata().attributes;
+    attributes.set_hidden(true);
+    vfat.set_attributes("/etc", attributes).expect("set");
+    let metadata = vfat.open_dir("/etc").expect("directory exists").metadata().clone();
+    assert_eq!(metadata.attributes.as_raw(), 0x12);
+
+    for &(path, raw) in &[("/etc/passwd", 0x10), ("/etc/passwd", 0x08), ("/etc", 0x00), ("/etc", 0x18)] {
+        let err = vfat.set_attributes(path, Attributes::from_raw(raw)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    let err = vfat.set_attributes("/etc/shadow", Attributes::from_raw(0)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    let err = vfat.set_attributes("/etc/..", Attributes::from_raw(0x10)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn volume_label() {
+    let image = SharedImage::new(mock_image());
+    let vfat = VFat::from(image.clone()).expect("valid mock image");
+    assert_eq!(vfat.borrow_mut().volume_label().unwrap(), None);
+
+    vfat.create_file("/before").expect("created");
+    vfat.borrow_mut().set_volume_label("Backup 01").expect("labeled");
+    assert_eq!(vfat.borrow_mut().volume_label().unwrap(), Some("BACKUP 01".to_string()));
+    vfat.create_file("/after").expect("created");
+    assert_eq!(root_names(&vfat), vec!["before", "after"]);
+
+    vfat.borrow_mut().set_volume_label("DATA").expect("relabeled");
+    assert_eq!(vfat.borrow_mut().volume_label().unwrap(), Some("DATA".to_string()));
+    vfat.borrow_mut().sync().expect("synced");
+
+    let bytes = image.bytes();
+    let label = PARTITION_START * SECTOR_SIZE + 71;
+    assert_eq!(&bytes[label..label + 11], b"DATA       ");
+    let root = (PARTITION_START + RESERVED_SECTORS + 2 * SECTORS_PER_FAT) * SECTOR_SIZE;
+    let volume_id_entries: Vec<&[u8]> = bytes[root..root + SECTOR_SIZE]
+        .chunks(32)
+        .filter(|entry| entry[0] != 0xE5 && entry[11] == 0x08)
+        .map(|entry| &entry[..11])
+        .collect();
+    assert_eq!(volume_id_entries, vec![&b"DATA       "[..]]);
+
+    for label in &["TWELVE CHARS", "a.b", " LEADING", "WHAT?"] {
+        let err = vfat.borrow_mut().set_volume_label(label).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    vfat.borrow_mut().set_volume_label("").expect("unlabeled");
+    assert_eq!(vfat.borrow_mut().volume_label().unwrap(), None);
+    vfat.borrow_mut().sync().expect("synced");
+    assert_eq!(&image.bytes()[label..label + 11], b"NO NAME    ");
+    assert_eq!(root_names(&vfat), vec!["before", "after"]);
+}
+
+#[test]
+fn volume_label_from_ebpb() {
+    let mut image = mock_image();
+    let label = PARTITION_START * SECTOR_SIZE + 71;
+    image[label..label + 11].copy_from_slice(b"FROM EBPB  ");
+    let vfat = VFat::from(Cursor::new(image)).expect("valid mock image");
+    assert_eq!(vfat.borrow_mut().volume_label().unwrap(), Some("FROM EBPB".to_string()));
+}